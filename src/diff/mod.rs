@@ -0,0 +1,216 @@
+//! Per-entity, per-field difference between two game data states,
+//! e.g. vanilla paks versus vanilla with mod paks on top.
+
+use std::collections::HashMap;
+use serde::Serialize;
+use serde_json::Value;
+use crate::{
+    entity::{
+        ability_processor, adv_object_processor, art_processor, art_set_processor, creature_bank_processor, creature_processor,
+        hero_class_processor, hero_processor, rules::{read_rules, RULES_FILE}, skill_processor, spell::{Spell, SpellKind},
+        spell_processor, town_processor, ScanProcessor, ScannedEntity
+    },
+    pak::Vfs
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldChange {
+    pub path: String,
+    pub old: Value,
+    pub new: Value
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntityDiff {
+    pub table: String,
+    pub key: String,
+    pub kind: ChangeKind,
+    /// Pak entity was read from, the old one for removed entities.
+    pub pak: String,
+    pub changes: Vec<FieldChange>
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DataDiff {
+    pub entities: Vec<EntityDiff>
+}
+
+impl DataDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for entity in &self.entities {
+            match entity.kind {
+                ChangeKind::Added => text += &format!("[{}] + {} ({})\n", entity.table, entity.key, entity.pak),
+                ChangeKind::Removed => text += &format!("[{}] - {} ({})\n", entity.table, entity.key, entity.pak),
+                ChangeKind::Changed => {
+                    text += &format!("[{}] ~ {} ({})\n", entity.table, entity.key, entity.pak);
+                    for change in &entity.changes {
+                        text += &format!("\t{}: {} -> {}\n", change.path, change.old, change.new);
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Runs all entity scaners on both states and compares results.
+/// Tables derived from other entities, like creature lineup or magic guilds, are not compared, their entities are.
+pub fn diff_states(before: &Vfs, after: &Vfs) -> DataDiff {
+    let mut diff = DataDiff::default();
+    diff_processors("creatures", creature_processor(), creature_processor(), before, after, &mut diff);
+    diff_processors("abilities", ability_processor(), ability_processor(), before, after, &mut diff);
+    diff_processors("artifacts", art_processor(), art_processor(), before, after, &mut diff);
+    diff_processors("artifact_sets", art_set_processor(), art_set_processor(), before, after, &mut diff);
+    // runes and warcries are in spells reftable, but have tables of their own
+    let before_spells = spell_processor().scan(before);
+    let after_spells = spell_processor().scan(after);
+    for (table, kind) in [("spells", SpellKind::Spell), ("runes", SpellKind::Rune), ("warcries", SpellKind::Warcry)] {
+        let of_kind = |spell: &&ScannedEntity<u16>| spell.get::<Spell>().is_some_and(|spell| spell.kind == kind);
+        diff.entities.extend(diff_keyed(
+            table,
+            keyed(before_spells.iter().filter(of_kind)),
+            keyed(after_spells.iter().filter(of_kind))
+        ));
+    }
+    diff_processors("heroes", hero_processor(), hero_processor(), before, after, &mut diff);
+    diff_processors("hero_classes", hero_class_processor(), hero_class_processor(), before, after, &mut diff);
+    diff_processors("skills", skill_processor(), skill_processor(), before, after, &mut diff);
+    diff_processors("towns", town_processor(), town_processor(), before, after, &mut diff);
+    diff_processors("adv_objects", adv_object_processor(), adv_object_processor(), before, after, &mut diff);
+    diff_processors("creature_banks", creature_bank_processor(), creature_bank_processor(), before, after, &mut diff);
+    diff.entities.extend(diff_keyed("rules", keyed_rules(before), keyed_rules(after)));
+    diff
+}
+
+/// Diffs output of two processors of the same kind, one per state.
+pub fn diff_processors<T: Clone + 'static>(
+    table: &str,
+    before_processor: ScanProcessor<T>,
+    after_processor: ScanProcessor<T>,
+    before: &Vfs,
+    after: &Vfs,
+    diff: &mut DataDiff
+) {
+    let before_entities = before_processor.scan(before);
+    let after_entities = after_processor.scan(after);
+    diff.entities.extend(diff_entities(table, &before_entities, &after_entities));
+}
+
+/// Compares two scanned sets of the same entity type.
+/// Entities are matched by identity(like reftable id), entities without one are matched by file key.
+/// Repeated keys get number of occurrence, like `Duncan#2`.
+pub fn diff_entities<T: 'static>(table: &str, before: &[ScannedEntity<T>], after: &[ScannedEntity<T>]) -> Vec<EntityDiff> {
+    diff_keyed(table, keyed(before.iter()), keyed(after.iter()))
+}
+
+/// Entity ready to be compared: key it is matched by, pak it was read from and its json.
+struct KeyedValue {
+    key: String,
+    pak: String,
+    value: Value
+}
+
+fn diff_keyed(table: &str, before: Vec<KeyedValue>, after: Vec<KeyedValue>) -> Vec<EntityDiff> {
+    let before_map: HashMap<&String, &KeyedValue> = before.iter().map(|entity| (&entity.key, entity)).collect();
+    let after_map: HashMap<&String, &KeyedValue> = after.iter().map(|entity| (&entity.key, entity)).collect();
+    let mut diffs = vec![];
+    for entity in &after {
+        match before_map.get(&entity.key) {
+            Some(old_entity) => {
+                let mut changes = vec![];
+                compare_values("", &old_entity.value, &entity.value, &mut changes);
+                if !changes.is_empty() {
+                    diffs.push(EntityDiff { table: table.to_string(), key: entity.key.clone(), kind: ChangeKind::Changed, pak: entity.pak.clone(), changes });
+                }
+            },
+            None => {
+                let mut changes = vec![];
+                compare_values("", &Value::Null, &entity.value, &mut changes);
+                diffs.push(EntityDiff { table: table.to_string(), key: entity.key.clone(), kind: ChangeKind::Added, pak: entity.pak.clone(), changes });
+            }
+        }
+    }
+    for entity in &before {
+        if !after_map.contains_key(&entity.key) {
+            let mut changes = vec![];
+            compare_values("", &entity.value, &Value::Null, &mut changes);
+            diffs.push(EntityDiff { table: table.to_string(), key: entity.key.clone(), kind: ChangeKind::Removed, pak: entity.pak.clone(), changes });
+        }
+    }
+    diffs
+}
+
+fn keyed<'a, T: 'static>(entities: impl Iterator<Item = &'a ScannedEntity<T>>) -> Vec<KeyedValue> {
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    entities
+        .map(|entity| {
+            let identity = entity.entity.identity().unwrap_or_else(|| entity.file_key.clone());
+            let occurrence = occurrences.entry(identity.clone()).or_default();
+            *occurrence += 1;
+            let key = if *occurrence > 1 { format!("{}#{}", identity, occurrence) } else { identity };
+            KeyedValue { key, pak: entity.pak.clone(), value: entity_value(entity) }
+        })
+        .collect()
+}
+
+/// Rules are single object, so they are keyed by their file.
+fn keyed_rules(files: &Vfs) -> Vec<KeyedValue> {
+    let rules = read_rules(files).and_then(|rules| serde_json::to_value(rules).ok());
+    match (rules, files.get(RULES_FILE)) {
+        (Some(value), Some(file)) => vec![KeyedValue { key: RULES_FILE.to_string(), pak: file.pak.clone(), value }],
+        _ => vec![]
+    }
+}
+
+fn entity_value<T: 'static>(entity: &ScannedEntity<T>) -> Value {
+    serde_json::from_str(&entity.entity.to_json()).unwrap_or(Value::Null)
+}
+
+/// Walks objects field by field, lists and scalars are compared as a whole.
+fn compare_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<FieldChange>) {
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            for (name, new_field) in new_fields {
+                compare_values(&join_path(path, name), old_fields.get(name).unwrap_or(&Value::Null), new_field, changes);
+            }
+            for (name, old_field) in old_fields.iter().filter(|(name, _)| !new_fields.contains_key(*name)) {
+                compare_values(&join_path(path, name), old_field, &Value::Null, changes);
+            }
+        },
+        (Value::Object(old_fields), Value::Null) => {
+            for (name, old_field) in old_fields {
+                compare_values(&join_path(path, name), old_field, &Value::Null, changes);
+            }
+        },
+        (Value::Null, Value::Object(new_fields)) => {
+            for (name, new_field) in new_fields {
+                compare_values(&join_path(path, name), &Value::Null, new_field, changes);
+            }
+        },
+        _ => {
+            if old != new {
+                changes.push(FieldChange { path: path.to_string(), old: old.clone(), new: new.clone() });
+            }
+        }
+    }
+}
+
+fn join_path(path: &str, name: &str) -> String {
+    if path.is_empty() { name.to_string() } else { format!("{}.{}", path, name) }
+}
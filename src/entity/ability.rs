@@ -0,0 +1,59 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::common::FileRef;
+use super::{read_reftable_entities, resolve_file_ref, FileStructure, ReftableEntity};
+use crate::impl_output;
+
+pub const ABILITIES_REFTABLE: &str = "gamemechanics/reftables/creatureabilities.xdb";
+
+/// Combat ability of creatures, like `ABILITY_LARGE_SHIELD`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AbilityShared {
+    /// Id of reftable item, not a part of ability element.
+    #[serde(default)]
+    pub ID: String,
+    pub NameFileRef: Option<FileRef>,
+    pub DescriptionFileRef: Option<FileRef>,
+    #[serde(alias = "Texture")]
+    pub Icon: Option<FileRef>
+}
+
+impl AbilityShared {
+    /// Ability as lua table, for lists of abilities in other entities.
+    pub fn to_lua_inline(&self) -> String {
+        format!(
+            "{{id = {}, name = {}, desc = {}, icon = {}}}",
+            self.ID,
+            crate::lua_value!(href self.NameFileRef),
+            crate::lua_value!(href self.DescriptionFileRef),
+            crate::lua_value!(href self.Icon)
+        )
+    }
+}
+
+impl_output! {
+    AbilityShared as ability, ID = String, key(_id) = value ability.ID, identity = Some(ability.ID.clone());
+    name: href = ability.NameFileRef,
+    desc: href = ability.DescriptionFileRef,
+    icon: href = ability.Icon
+}
+
+impl ReftableEntity for AbilityShared {
+    const NAME: &'static str = "ability";
+
+    fn complete(mut self, id: String, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<Self> {
+        self.ID = id;
+        resolve_file_ref(&mut self.NameFileRef, file_key, files);
+        resolve_file_ref(&mut self.DescriptionFileRef, file_key, files);
+        resolve_file_ref(&mut self.Icon, file_key, files);
+        Some(self)
+    }
+}
+
+/// All abilities of reftable by their ids.
+pub fn read_abilities(files: &HashMap<String, FileStructure>) -> HashMap<String, AbilityShared> {
+    read_reftable_entities::<AbilityShared>(files, ABILITIES_REFTABLE).into_iter()
+        .map(|ability| (ability.ID.clone(), ability))
+        .collect()
+}
@@ -0,0 +1,128 @@
+//! Shared definitions of adventure map objects: mines, visitable buildings, dwellings and shrines.
+//! Every kind has its own root element, parameters of other kinds are just absent in its file.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::common::FileRef;
+use super::{art::HeroStatsModif, deserialize_element, resolve_file_ref, CollectFiles, CollectedFile, FileStructure, Items, Output, Scan, Vfs};
+use crate::{impl_output, output::lua_list, xdb::root_element};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdvObjectKind {
+    /// `AdvMapMineShared`
+    Mine,
+    /// `AdvMapBuildingShared`
+    #[default]
+    Building,
+    /// `AdvMapDwellingShared`
+    Dwelling,
+    /// `AdvMapShrineShared`
+    Shrine
+}
+
+impl AdvObjectKind {
+    pub const ALL: [AdvObjectKind; 4] = [AdvObjectKind::Mine, AdvObjectKind::Building, AdvObjectKind::Dwelling, AdvObjectKind::Shrine];
+
+    pub fn root(&self) -> &'static str {
+        match self {
+            AdvObjectKind::Mine => "AdvMapMineShared",
+            AdvObjectKind::Building => "AdvMapBuildingShared",
+            AdvObjectKind::Dwelling => "AdvMapDwellingShared",
+            AdvObjectKind::Shrine => "AdvMapShrineShared"
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdvObjectKind::Mine => "mine",
+            AdvObjectKind::Building => "building",
+            AdvObjectKind::Dwelling => "dwelling",
+            AdvObjectKind::Shrine => "shrine"
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct AdvObjectShared {
+    /// Key of definition file, objects have no ids.
+    #[serde(skip_deserializing)]
+    pub key: String,
+    #[serde(skip_deserializing)]
+    pub kind: AdvObjectKind,
+    pub NameFileRef: Option<FileRef>,
+    pub DescriptionFileRef: Option<FileRef>,
+    #[serde(alias = "Texture")]
+    pub Icon: Option<FileRef>,
+    /// Resource mine produces, like `GOLD`.
+    #[serde(alias = "ResourceType")]
+    pub Resource: Option<String>,
+    /// Amount of resource produced daily.
+    pub Income: u16,
+    /// Changes of stats of visiting hero.
+    pub HeroStatsModif: HeroStatsModif,
+    /// Creatures dwelling hires.
+    pub Creatures: Items<String>,
+    /// Level of spells shrine teaches.
+    pub SpellLevel: u8
+}
+
+impl AdvObjectShared {
+    /// Parameters that make sense for kind of object.
+    fn lua_params(&self) -> String {
+        match self.kind {
+            AdvObjectKind::Mine => format!("{{resource = {}, income = {}}}", self.Resource.as_deref().unwrap_or("nil"), self.Income),
+            AdvObjectKind::Building => format!("{{stats = {}}}", self.HeroStatsModif.to_lua()),
+            AdvObjectKind::Dwelling => format!("{{creatures = {}}}", lua_list(self.Creatures.items.iter().flatten(), |creature| creature.to_string())),
+            AdvObjectKind::Shrine => format!("{{spell_level = {}}}", self.SpellLevel)
+        }
+    }
+}
+
+impl_output! {
+    AdvObjectShared as object, ID = String, key(_id) = string object.key;
+    type: string = object.kind.as_str(),
+    name: href = object.NameFileRef,
+    desc: href = object.DescriptionFileRef,
+    icon: href = object.Icon,
+    params: value = object.lua_params()
+}
+
+pub struct AdvObjectFileCollector {}
+
+impl CollectFiles for AdvObjectFileCollector {
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        for kind in AdvObjectKind::ALL {
+            collected_files.extend(files.files_with_root(kind.root()).map(|f| CollectedFile::new(f.0, f.1)));
+        }
+    }
+}
+
+pub struct AdvObjectScaner {}
+
+impl Scan<String> for AdvObjectScaner {
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = String>>> {
+        let root = root_element(entity)?;
+        let kind = AdvObjectKind::ALL.into_iter().find(|kind| kind.root() == root)?;
+        match deserialize_element::<AdvObjectShared>(entity, kind.root())? {
+            Ok(mut object) => {
+                object.key = file_key.to_string();
+                object.kind = kind;
+                resolve_file_ref(&mut object.NameFileRef, file_key, files);
+                resolve_file_ref(&mut object.DescriptionFileRef, file_key, files);
+                resolve_file_ref(&mut object.Icon, file_key, files);
+                Some(Box::new(object))
+            }
+            Err(e) => {
+                crate::diagnostic!("error while deserializing file key {}, {:?}", file_key, e.to_string());
+                None
+            }
+        }
+    }
+
+    fn get_id(&self, _position: usize) -> Option<String> {
+        None
+    }
+}
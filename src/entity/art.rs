@@ -1,40 +1,127 @@
 use serde::{Serialize, Deserialize};
-use super::{Scan, Output, FileStructure, CollectFiles};
-use quick_xml::{Reader, events::Event};
-use std::collections::HashMap;
-use homm5_types::art::AdvMapArtifactShared;
+use super::{
+    art_set::artifact_set_ids, deserialize_element,
+    Scan, Output, FileStructure, CollectFiles, CollectedFile, Items, ReftableItem, ReftableItemCollector, RunCache, Vfs
+};
+use crate::impl_output;
+use std::{collections::HashMap, ops::Deref};
+use homm5_types::{art::AdvMapArtifactShared, common::{FileRef, SkillMastery}, creature::Spell};
 
-impl Output for AdvMapArtifactShared {
-    type ID = u16;
+pub const ARTIFACTS_REFTABLE: &str = "gamemechanics/reftables/artifacts.xdb";
 
-    fn to_lua(&self, id: Option<Self::ID>) -> String {
-        let is_sellable = if self.CanBeGeneratedToSell == true {"1"} else {"nil"};
+/// Changes of hero stats.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct HeroStatsModif {
+    pub Attack: i16,
+    pub Defence: i16,
+    pub SpellPower: i16,
+    pub Knowledge: i16,
+    pub Luck: i16,
+    pub Morale: i16
+}
+
+impl HeroStatsModif {
+    pub fn to_lua(&self) -> String {
         format!(
-            "\t[{}] = {{
-        is_sellable = {},
-        name = \"{}\",
-        desc = \"{}\",
-        icon = \"{}\",
-        cost = {},
-        slot = {},
-        type = {}
-    }},\n", 
-            id.unwrap() - 1,
-            is_sellable,
-            self.NameFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()), 
-            self.DescriptionFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.Icon.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.CostOfGold,
-            self.Slot,
-            self.Type
+            "{{attack = {}, defence = {}, spellpower = {}, knowledge = {}, luck = {}, morale = {}}}",
+            self.Attack, self.Defence, self.SpellPower, self.Knowledge, self.Luck, self.Morale
         )
     }
+}
+
+/// Everything artifact gives to hero wearing it, not read by `AdvMapArtifactShared`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct ArtifactBonuses {
+    pub HeroStatsModif: HeroStatsModif,
+    /// Skills with masteries.
+    pub Skills: Items<SkillMastery>,
+    /// Spells hero can cast, with masteries.
+    pub Spells: Items<Spell>
+}
+
+/// Artifact element of reftable item read in one pass, it is split into `AdvMapArtifactShared` and `ArtifactBonuses`.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ArtifactElement {
+    NameFileRef: Option<FileRef>,
+    DescriptionFileRef: Option<FileRef>,
+    Type: String,
+    Slot: String,
+    Icon: Option<FileRef>,
+    CostOfGold: u32,
+    CanBeGeneratedToSell: bool,
+    #[serde(default)]
+    HeroStatsModif: HeroStatsModif,
+    #[serde(default)]
+    Skills: Items<SkillMastery>,
+    #[serde(default)]
+    Spells: Items<Spell>
+}
+
+impl ArtifactElement {
+    fn split(self) -> (AdvMapArtifactShared, ArtifactBonuses) {
+        let shared = AdvMapArtifactShared {
+            NameFileRef: self.NameFileRef,
+            DescriptionFileRef: self.DescriptionFileRef,
+            Type: self.Type,
+            Slot: self.Slot,
+            Icon: self.Icon,
+            CostOfGold: self.CostOfGold,
+            CanBeGeneratedToSell: self.CanBeGeneratedToSell
+        };
+        let bonuses = ArtifactBonuses {
+            HeroStatsModif: self.HeroStatsModif,
+            Skills: self.Skills,
+            Spells: self.Spells
+        };
+        (shared, bonuses)
+    }
+}
+
+/// Artifact together with its reftable id, bonuses and set it belongs to.
+#[derive(Debug, Serialize)]
+pub struct Artifact {
+    #[serde(flatten)]
+    pub shared: AdvMapArtifactShared,
+    #[serde(flatten)]
+    pub bonuses: ArtifactBonuses,
+    pub id: String,
+    /// Id of artifact set, like `ARTIFACT_SET_DRAGON`.
+    pub set: Option<String>
+}
 
-    fn to_json(&self) -> String {
-        serde_json::to_string_pretty(self).unwrap()
+impl Deref for Artifact {
+    type Target = AdvMapArtifactShared;
+
+    fn deref(&self) -> &AdvMapArtifactShared {
+        &self.shared
     }
 }
 
+impl_output! {
+    Artifact as art, ID = u16, key(id) = value id.unwrap() - 1, identity = Some(art.id.clone());
+    is_sellable: bool = art.CanBeGeneratedToSell,
+    name: href = art.NameFileRef,
+    desc: href = art.DescriptionFileRef,
+    icon: href = art.Icon,
+    cost: value = art.CostOfGold,
+    slot: value = art.Slot,
+    type: value = art.Type,
+    set: value = art.set.as_deref().unwrap_or("nil"),
+    attack: value = art.bonuses.HeroStatsModif.Attack,
+    defence: value = art.bonuses.HeroStatsModif.Defence,
+    spellpower: value = art.bonuses.HeroStatsModif.SpellPower,
+    knowledge: value = art.bonuses.HeroStatsModif.Knowledge,
+    luck: value = art.bonuses.HeroStatsModif.Luck,
+    morale: value = art.bonuses.HeroStatsModif.Morale,
+    skills: list = art.bonuses.Skills.items => |skill| format!("[{}] = {}", skill.skill_id, skill.mastery),
+    spells: list = art.bonuses.Spells.items => |spell| format!("[{}] = {}", spell.Spell, spell.Mastery)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct ArtObject {
@@ -51,62 +138,38 @@ pub struct ArtObjects {
 pub struct ArtFileCollector {}
 
 impl CollectFiles for ArtFileCollector {
-    fn collect(&self, files: &HashMap<String, FileStructure>, collected_files: &mut Vec<(String, FileStructure)>) {
-        let arts_xdb = files.iter()
-            .find(|f| f.0 == "GameMechanics/RefTables/Artifacts.xdb".to_lowercase().as_str())
-            .unwrap();
-        //collected_files.push((arts_xdb.0.clone(), arts_xdb.1.clone()));
-        let mut buf = Vec::new();
-        let mut reader = Reader::from_str(arts_xdb.1.content.as_str());
-        reader.trim_text(true);
-        reader.expand_empty_elements(true);
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                Ok(Event::Eof) => break,
-                Ok(Event::Start(e)) => {
-                    match e.name().as_ref() {
-                        b"obj" => {
-                            let end = e.to_end().into_owned();
-                            let text = reader.read_text(end.name()).unwrap().to_string();
-                            let text = format!("<obj>{}</obj>", text);
-                            collected_files.push(("GameMechanics/RefTables/Artifacts.xdb".to_lowercase(), FileStructure{
-                                pak: arts_xdb.1.pak.clone(),
-                                modified: arts_xdb.1.modified,
-                                content: text
-                            }));
-                        }
-                        _=> {}
-                    }
-                }
-                _ => ()
-            }
-            buf.clear();
-        }
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        // artifacts are inline, each one is a slice of reftable with its ID and obj
+        ReftableItemCollector { reftable: ARTIFACTS_REFTABLE.to_string() }.collect(files, collected_files);
     }
 }
 
 pub struct ArtScaner {
     pub id: u16,
+    /// Ids of sets by ids of artifacts.
+    pub sets: RunCache<HashMap<String, String>>
 }
 
 impl Scan<u16> for ArtScaner {
-    fn get_id(&self) -> Option<u16> {
-        Some(self.id)
+    fn get_id(&self, position: usize) -> Option<u16> {
+        Some(self.id + position as u16)
     }
 
-    #[allow(unused_variables)]
-    fn scan(&mut self, file_key: &String, entity: &String, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
-        let art_de: Result<AdvMapArtifactShared, quick_xml::DeError> = quick_xml::de::from_str(entity);
-        match art_de {
-            Ok(art) => {
-                self.id += 1;
-                Some(Box::new(art))
-            }
+    fn prepare(&self) {
+        self.sets.clear();
+    }
+
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
+        match deserialize_element::<ReftableItem<ArtifactElement>>(entity, "Item")? {
+            Ok(item) => {
+                let (shared, bonuses) = item.obj?.split();
+                let set = self.sets.get_or_init(|| artifact_set_ids(files)).get(&item.ID).cloned();
+                Some(Box::new(Artifact { shared, bonuses, id: item.ID, set }))
+            },
             Err(e) => {
-                println!("error deserializing artifact {}", e.to_string());
+                crate::diagnostic!("error deserializing artifact of {}, {}", file_key, e);
                 None
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,80 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::common::FileRef;
+use super::{art::HeroStatsModif, read_reftable_items, resolve_file_ref, FileStructure, Items, ReftableEntity};
+use crate::{impl_output, output::lua_list};
+
+pub const ARTIFACT_SETS_REFTABLE: &str = "gamemechanics/reftables/artifactsets.xdb";
+
+/// Bonus hero gets when wearing `PiecesCount` artifacts of set.
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SetBonus {
+    pub PiecesCount: u8,
+    pub DescriptionFileRef: Option<FileRef>,
+    #[serde(default)]
+    pub HeroStatsModif: HeroStatsModif
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ArtifactSetShared {
+    /// Id of reftable item, not a part of set element.
+    #[serde(default)]
+    pub ID: String,
+    pub NameFileRef: Option<FileRef>,
+    pub DescriptionFileRef: Option<FileRef>,
+    #[serde(default)]
+    pub Artifacts: Items<String>,
+    #[serde(default)]
+    pub Bonuses: Items<SetBonus>
+}
+
+impl ArtifactSetShared {
+    pub fn artifacts(&self) -> impl Iterator<Item = &String> {
+        self.Artifacts.items.iter().flatten()
+    }
+
+    fn lua_bonuses(&self) -> String {
+        lua_list(self.Bonuses.items.iter().flatten(), |bonus| format!(
+            "[{}] = {{desc = {}, stats = {}}}",
+            bonus.PiecesCount, crate::lua_value!(href bonus.DescriptionFileRef), bonus.HeroStatsModif.to_lua()
+        ))
+    }
+}
+
+impl_output! {
+    ArtifactSetShared as set, ID = String, key(_id) = value set.ID, identity = Some(set.ID.clone());
+    name: href = set.NameFileRef,
+    desc: href = set.DescriptionFileRef,
+    artifacts: list = set.Artifacts.items,
+    bonuses: value = set.lua_bonuses()
+}
+
+impl ReftableEntity for ArtifactSetShared {
+    const NAME: &'static str = "artifact set";
+
+    fn complete(mut self, id: String, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<Self> {
+        self.ID = id;
+        resolve_file_ref(&mut self.NameFileRef, file_key, files);
+        resolve_file_ref(&mut self.DescriptionFileRef, file_key, files);
+        for bonus in self.Bonuses.items.iter_mut().flatten() {
+            resolve_file_ref(&mut bonus.DescriptionFileRef, file_key, files);
+        }
+        Some(self)
+    }
+}
+
+/// Ids of sets by ids of artifacts in them.
+pub fn artifact_set_ids(files: &HashMap<String, FileStructure>) -> HashMap<String, String> {
+    if !files.contains_key(ARTIFACT_SETS_REFTABLE) {
+        return HashMap::new();
+    }
+    let mut sets = HashMap::new();
+    for item in read_reftable_items::<ArtifactSetShared>(files, ARTIFACT_SETS_REFTABLE) {
+        for artifact in item.obj.iter().flat_map(|set| set.artifacts()) {
+            sets.entry(artifact.clone()).or_insert_with(|| item.ID.clone());
+        }
+    }
+    sets
+}
@@ -0,0 +1,116 @@
+//! Creature banks: guard variants of bank with chances to meet them and rewards for beating them.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::{common::{ArmySlot, FileRef}, creature::Resources};
+use super::{creature::creature_powers, deserialize_element, resolve_file_ref, town::lua_cost, CollectFiles, CollectedFile, FileStructure, Items, Output, RunCache, Scan, Vfs};
+use crate::{impl_output, output::lua_list};
+
+pub const CREATURE_BANK_ROOT: &str = "AdvMapCreatureBankShared";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct BankVariant {
+    /// Chance(in percents) bank is guarded by this variant.
+    #[serde(default)]
+    pub Chance: u8,
+    #[serde(default)]
+    pub Guards: Items<ArmySlot>,
+    pub Resources: Option<Resources>,
+    #[serde(default)]
+    pub Artifacts: Items<String>,
+    /// Creatures joining hero.
+    #[serde(default)]
+    pub Creatures: Items<ArmySlot>,
+    /// Sum of power of guard stacks, guards missing in creatures reftable are not counted.
+    #[serde(skip_deserializing)]
+    pub guard_power: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct CreatureBankShared {
+    /// Key of definition file, banks have no ids.
+    #[serde(skip_deserializing)]
+    pub key: String,
+    pub NameFileRef: Option<FileRef>,
+    pub DescriptionFileRef: Option<FileRef>,
+    #[serde(alias = "Texture")]
+    pub Icon: Option<FileRef>,
+    #[serde(default)]
+    pub Variants: Items<BankVariant>
+}
+
+fn lua_stacks(stacks: &Items<ArmySlot>) -> String {
+    lua_list(stacks.items.iter().flatten(), |stack| format!("{{creature = {}, count = {}}}", stack.creature, stack.count))
+}
+
+impl CreatureBankShared {
+    fn lua_variants(&self) -> String {
+        lua_list(self.Variants.items.iter().flatten(), |variant| format!(
+            "{{chance = {}, guards = {}, guard_power = {}, resources = {}, artifacts = {}, creatures = {}}}",
+            variant.Chance,
+            lua_stacks(&variant.Guards),
+            variant.guard_power,
+            variant.Resources.as_ref().map(lua_cost).unwrap_or_else(|| "{}".to_string()),
+            lua_list(variant.Artifacts.items.iter().flatten(), |artifact| artifact.to_string()),
+            lua_stacks(&variant.Creatures)
+        ))
+    }
+}
+
+impl_output! {
+    CreatureBankShared as bank, ID = String, key(_id) = string bank.key;
+    name: href = bank.NameFileRef,
+    desc: href = bank.DescriptionFileRef,
+    icon: href = bank.Icon,
+    variants: value = bank.lua_variants()
+}
+
+pub struct CreatureBankFileCollector {}
+
+impl CollectFiles for CreatureBankFileCollector {
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        collected_files.extend(files.files_with_root(CREATURE_BANK_ROOT).map(|f| CollectedFile::new(f.0, f.1)));
+    }
+}
+
+pub struct CreatureBankScaner {
+    /// Powers of creatures by their ids.
+    pub powers: RunCache<HashMap<String, u64>>
+}
+
+impl Scan<String> for CreatureBankScaner {
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = String>>> {
+        match deserialize_element::<CreatureBankShared>(entity, CREATURE_BANK_ROOT)? {
+            Ok(mut bank) => {
+                bank.key = file_key.to_string();
+                resolve_file_ref(&mut bank.NameFileRef, file_key, files);
+                resolve_file_ref(&mut bank.DescriptionFileRef, file_key, files);
+                resolve_file_ref(&mut bank.Icon, file_key, files);
+                let powers = self.powers.get_or_init(|| creature_powers(files));
+                for variant in bank.Variants.items.iter_mut().flatten() {
+                    for guard in variant.Guards.items.iter().flatten() {
+                        match powers.get(&guard.creature) {
+                            Some(power) => variant.guard_power += power * guard.count as u64,
+                            None => crate::diagnostic!("Guard {} of bank {} is not in creatures reftable", guard.creature, file_key)
+                        }
+                    }
+                }
+                Some(Box::new(bank))
+            }
+            Err(e) => {
+                crate::diagnostic!("error while deserializing file key {}, {:?}", file_key, e.to_string());
+                None
+            }
+        }
+    }
+
+    fn get_id(&self, _position: usize) -> Option<String> {
+        None
+    }
+
+    fn prepare(&self) {
+        self.powers.clear();
+    }
+}
@@ -0,0 +1,337 @@
+//! Entity scaner driven by data instead of code.
+//! Spec describes where entities are, which element contains them and which fields go to lua, e.g.
+//! ```json
+//! {
+//!     "table_name": "MCCS_HERO_CLASSES",
+//!     "output_file_name": "hero_classes.lua",
+//!     "source": { "reftable": { "path": "GameMechanics/RefTables/HeroClass.xdb" } },
+//!     "root": "Item",
+//!     "key": "ID",
+//!     "fields": [
+//!         { "path": "obj.NameFileRef.@href", "key": "name", "kind": "href" },
+//!         { "path": "obj.AttributeProbs.OffenceProb", "key": "attack_chance" }
+//!     ]
+//! }
+//! ```
+
+use std::{collections::HashMap, sync::Arc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use quick_xml::{Reader, events::Event};
+use crate::{
+    entity::{configure_path, CollectFiles, CollectedFile, Output, Scan, ScanProcessor},
+    output::{lua_bool, lua_entry, lua_list},
+    pak::{FileStructure, Vfs},
+    query::lookup,
+    xdb::element_to_value
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Items of reftable, either referencing separate files or containing entities inline.
+    Reftable { path: String },
+    /// Every xdb file with given root element.
+    RootTag { tag: String }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    /// Written as is.
+    #[default]
+    Value,
+    /// Written in quotes.
+    String,
+    /// `1` or `nil`.
+    Bool,
+    /// Reference resolved to file key, written in quotes.
+    Href,
+    /// `<Item>` list written as lua list.
+    List
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldSpec {
+    /// Dot separated path in entity element, attributes are prefixed with `@`, e.g. `Icon.@href`.
+    pub path: String,
+    /// Name of field in lua table.
+    pub key: String,
+    #[serde(default)]
+    pub kind: FieldKind
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScannerSpec {
+    pub table_name: String,
+    pub output_file_name: String,
+    pub source: Source,
+    /// Element containing entity: root of referenced file or inline element of reftable item.
+    pub root: String,
+    /// Only reftable references with this xpointer are followed, `#xpointer(/<root>)` if not set.
+    #[serde(default)]
+    pub xpointer: Option<String>,
+    /// Path of field used as lua key. Entities are numbered in order of scan if not set.
+    #[serde(default)]
+    pub key: Option<String>,
+    pub fields: Vec<FieldSpec>
+}
+
+impl ScannerSpec {
+    pub fn from_json(spec: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(spec)
+    }
+
+    pub fn from_toml(spec: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(spec)
+    }
+
+    pub fn processor(self) -> ScanProcessor<u16> {
+        let spec = Arc::new(self);
+        ScanProcessor::new(
+            spec.table_name.clone(),
+            spec.output_file_name.clone(),
+            Box::new(ConfiguredFileCollector { spec: spec.clone() }),
+            Box::new(ConfiguredScaner { spec, id: 0 })
+        )
+    }
+
+    fn xpointer(&self) -> String {
+        self.xpointer.clone().unwrap_or_else(|| format!("#xpointer(/{})", self.root))
+    }
+}
+
+pub struct ConfiguredFileCollector {
+    pub spec: Arc<ScannerSpec>
+}
+
+impl CollectFiles for ConfiguredFileCollector {
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        match &self.spec.source {
+            Source::RootTag { tag } => {
+                collected_files.extend(files.files_with_root(tag).map(|f| CollectedFile::new(f.0, f.1)));
+            },
+            Source::Reftable { path } => {
+                let reftable_key = path.trim_start_matches('/').to_lowercase();
+                let (reftable_key, reftable) = match files.get_key_value(&reftable_key) {
+                    Some(reftable) => reftable,
+                    None => {
+                        crate::diagnostic!("Reftable {} is not in files", path);
+                        return;
+                    }
+                };
+                let xpointer = self.spec.xpointer();
+                let mut reader = Reader::from_str(&reftable.content);
+                reader.trim_text(true);
+                reader.expand_empty_elements(true);
+                loop {
+                    let start = reader.buffer_position();
+                    match reader.read_event() {
+                        Err(e) => {
+                            crate::diagnostic!("Error reading {} at position {}: {:?}", path, reader.buffer_position(), e);
+                            break;
+                        },
+                        Ok(Event::Eof) => break,
+                        Ok(Event::Start(e)) if e.name().as_ref() == self.spec.root.as_bytes() => {
+                            // entity is inline, pass its part of reftable with the reftable key
+                            let end = e.to_end().into_owned();
+                            if let Err(e) = reader.read_to_end(end.name()) {
+                                crate::diagnostic!("Error reading {} at position {}: {:?}", path, reader.buffer_position(), e);
+                                break;
+                            }
+                            collected_files.push(CollectedFile {
+                                key: reftable_key,
+                                pak: &reftable.pak,
+                                content: &reftable.content[start..reader.buffer_position()]
+                            });
+                        },
+                        Ok(Event::Start(e)) => {
+                            let href = e.try_get_attribute("href").ok().flatten()
+                                .and_then(|href| href.unescape_value().ok().map(|h| h.to_string()));
+                            if let Some(href) = href.filter(|href| href.ends_with(&xpointer)) {
+                                let key = href.replace(&xpointer, "").trim_start_matches('/').to_lowercase();
+                                match files.get_key_value(&key) {
+                                    Some((key, entity)) => collected_files.push(CollectedFile::new(key, entity)),
+                                    None => crate::diagnostic!("Key {} is not in files", &key)
+                                }
+                            }
+                        },
+                        _ => ()
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct ConfiguredScaner {
+    pub spec: Arc<ScannerSpec>,
+    pub id: u16
+}
+
+impl Scan<u16> for ConfiguredScaner {
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
+        let mut value = element_to_value(entity, &self.spec.root)?;
+        let mut lua_fields = vec![];
+        for field in &self.spec.fields {
+            if field.kind == FieldKind::Href {
+                let href = lookup(&value, &field.path).as_str().unwrap_or_default().to_string();
+                let resolved = configure_path(Some(&href.split('#').next().unwrap_or_default().to_string()), file_key, files);
+                if let Some(field_value) = value.pointer_mut(&format!("/{}", field.path.replace('.', "/"))) {
+                    *field_value = Value::String(resolved);
+                }
+            }
+            lua_fields.push((field.key.clone(), lua_field(lookup(&value, &field.path), field.kind)));
+        }
+        let key = self.spec.key.as_ref().map(|key| lookup(&value, key).as_str().unwrap_or_default().to_string());
+        Some(Box::new(ConfiguredEntity { key, value, lua_fields }))
+    }
+
+    fn get_id(&self, position: usize) -> Option<u16> {
+        Some(self.id + position as u16)
+    }
+}
+
+/// Entity scanned by spec: element as json and its lua fields.
+pub struct ConfiguredEntity {
+    pub key: Option<String>,
+    pub value: Value,
+    pub lua_fields: Vec<(String, String)>
+}
+
+impl Output for ConfiguredEntity {
+    type ID = u16;
+
+    fn to_lua(&self, id: Option<u16>) -> String {
+        let key = match &self.key {
+            Some(key) => format!("\"{}\"", key),
+            None => id.unwrap_or_default().to_string()
+        };
+        let fields: Vec<(&str, String)> = self.lua_fields.iter().map(|(name, value)| (name.as_str(), value.clone())).collect();
+        lua_entry(&key, &fields)
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.value).unwrap()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.key.clone()
+    }
+}
+
+fn lua_field(value: &Value, kind: FieldKind) -> String {
+    match kind {
+        FieldKind::Value => match value {
+            Value::Null => "nil".to_string(),
+            other => text(other)
+        },
+        FieldKind::String | FieldKind::Href => format!("\"{}\"", text(value)),
+        FieldKind::Bool => lua_bool(matches!(value, Value::Bool(true)) || value.as_str() == Some("true")).to_string(),
+        FieldKind::List => lua_list(value.as_array().into_iter().flatten(), text)
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::capture;
+
+    fn files(documents: &[(&str, &str)]) -> Vfs {
+        Vfs::from_files(documents.iter()
+            .map(|(key, content)| (key.to_string(), FileStructure { pak: "data.pak".to_string(), modified: 0, content: content.to_string() }))
+            .collect())
+    }
+
+    fn spec(source: Source, root: &str, key: Option<&str>, fields: &[(&str, &str, FieldKind)]) -> ScannerSpec {
+        ScannerSpec {
+            table_name: "TEST".to_string(),
+            output_file_name: "test.lua".to_string(),
+            source,
+            root: root.to_string(),
+            xpointer: None,
+            key: key.map(str::to_string),
+            fields: fields.iter()
+                .map(|(path, key, kind)| FieldSpec { path: path.to_string(), key: key.to_string(), kind: *kind })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn inline_reftable_entities() {
+        let files = files(&[("tables/classes.xdb", r#"<Table><objects>
+            <Item><ID>CLASS_KNIGHT</ID><obj><Town>TOWN_HEAVEN</Town><Skills><Item>SKILL_A</Item><Item>SKILL_B</Item></Skills></obj></Item>
+            <Item><ID>CLASS_RANGER</ID><obj><Town>TOWN_PRESERVE</Town><Skills/></obj></Item>
+        </objects></Table>"#)]);
+        let spec = spec(Source::Reftable { path: "/Tables/Classes.xdb".to_string() }, "Item", Some("ID"), &[
+            ("obj.Town", "town", FieldKind::Value),
+            ("obj.Skills", "skills", FieldKind::List)
+        ]);
+        let (lua, _) = spec.processor().run(&files);
+        assert_eq!(lua, "TEST = {\n\
+            \t[\"CLASS_KNIGHT\"] = {\n        town = TOWN_HEAVEN,\n        skills = {SKILL_A, SKILL_B, }\n    },\n\
+            \t[\"CLASS_RANGER\"] = {\n        town = TOWN_PRESERVE,\n        skills = {}\n    },\n}");
+    }
+
+    #[test]
+    fn reftable_references_are_followed() {
+        let files = files(&[
+            ("tables/spells.xdb", r#"<Table><objects>
+                <Item><ID>SPELL_A</ID><Obj href="/Spells/A.xdb#xpointer(/Spell)"/></Item>
+                <Item><ID>SPELL_NONE</ID><Obj/></Item>
+                <Item><ID>SPELL_B</ID><Obj href="/Spells/B.xdb#xpointer(/Spell)"/></Item>
+                <Item><ID>SPELL_C</ID><Obj href="/Spells/C.xdb#xpointer(/Spell)"/></Item>
+            </objects></Table>"#),
+            ("spells/a.xdb", r#"<Spell><Level>1</Level><NameFileRef href="A.txt"/></Spell>"#),
+            ("spells/a.txt", "A"),
+            ("spells/b.xdb", r#"<Spell><Level>3</Level><NameFileRef href="/Text/B.txt"/></Spell>"#)
+        ]);
+        let spec = spec(Source::Reftable { path: "Tables/Spells.xdb".to_string() }, "Spell", None, &[
+            ("Level", "level", FieldKind::Value),
+            ("NameFileRef.@href", "name", FieldKind::Href)
+        ]);
+        let ((lua, _), messages) = capture(|| spec.processor().run(&files));
+        assert_eq!(lua, "TEST = {\n\
+            \t[1] = {\n        level = 1,\n        name = \"spells/a.txt\"\n    },\n\
+            \t[2] = {\n        level = 3,\n        name = \"spells/text/b.txt\"\n    },\n}");
+        assert_eq!(messages, [
+            "Key spells/c.xdb is not in files",
+            "Path text/b.txt referenced by spells/b.xdb is not in files"
+        ]);
+    }
+
+    #[test]
+    fn files_with_root_tag() {
+        let files = files(&[
+            ("objects/b.xdb", "<AdvMapBuildingShared><Type>SHRINE</Type></AdvMapBuildingShared>"),
+            ("objects/a.xdb", "<AdvMapBuildingShared><Type>TEMPLE</Type></AdvMapBuildingShared>"),
+            ("objects/c.xdb", "<AdvMapTreasureShared><Type>CHEST</Type></AdvMapTreasureShared>")
+        ]);
+        let spec = spec(Source::RootTag { tag: "AdvMapBuildingShared".to_string() }, "AdvMapBuildingShared", None, &[
+            ("Type", "kind", FieldKind::String)
+        ]);
+        let (lua, json) = spec.processor().run(&files);
+        assert_eq!(lua, "TEST = {\n\
+            \t[1] = {\n        kind = \"TEMPLE\"\n    },\n\
+            \t[2] = {\n        kind = \"SHRINE\"\n    },\n}");
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), serde_json::json!([{"Type": "TEMPLE"}, {"Type": "SHRINE"}]));
+    }
+
+    #[test]
+    fn truncated_reftable_is_reported() {
+        let files = files(&[("tables/classes.xdb", "<Table><objects><Item><ID>CLASS_KNIGHT</ID><obj><Town>TOWN_HEAVEN</Town>")]);
+        let spec = spec(Source::Reftable { path: "tables/classes.xdb".to_string() }, "Item", Some("ID"), &[]);
+        let ((lua, _), messages) = capture(|| spec.processor().run(&files));
+        assert_eq!(lua, "TEST = {\n}");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("Error reading tables/classes.xdb"));
+    }
+}
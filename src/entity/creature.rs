@@ -1,276 +1,321 @@
-use super::{configure_path, CollectFiles, FileObjects, FileRef, FileStructure, Output, Scan};
-use quick_xml::{Reader, events::Event};
-use std::collections::HashMap;
+use super::{
+    ability::{read_abilities, AbilityShared},
+    configure_path, creature_processor, deserialize_element, read_reftable, resolve_file_ref,
+    CollectFiles, CollectedFile, FileObjects, FileStructure, Items, Output, RunCache, Scan, ScannedEntity, Vfs
+};
+use crate::{export::{Export, ExportedTable, ScanCache}, impl_output, output::{lua_entry, lua_list}};
+use std::{collections::{BTreeMap, HashMap}, ops::Deref};
+use serde::{Serialize, Deserialize};
 use homm5_types::creature::{CreatureVisual, AdvMapCreatureShared};
 
-pub struct CreatureFileCollector {}
+pub const CREATURES_REFTABLE: &str = "GameMechanics/RefTables/Creatures.xdb";
 
-impl CollectFiles for CreatureFileCollector {
-    fn collect(&self, files: &HashMap<String, FileStructure>, collected_files: &mut Vec<(String, FileStructure)>){
-        let creatures_xdb = files.iter()
-            .find(|f| f.0 == "GameMechanics/RefTables/Creatures.xdb".to_lowercase().as_str())
-            .unwrap();
-        println!("creatures xdb pak - {}", &creatures_xdb.1.pak);
-        let mut buf = Vec::new();
-        let mut reader = Reader::from_str(creatures_xdb.1.content.as_str());
-        reader.trim_text(true);
-        reader.expand_empty_elements(true);
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                Ok(Event::Eof) => break,
-                Ok(Event::Start(e)) => {
-                    match e.name().as_ref() {
-                        b"objects" => {
-                            let end = e.to_end().into_owned();
-                            let text = reader.read_text(end.name()).unwrap().to_string();
-                            let text = format!("<objects>{}</objects>", text);
-                            let creatures_de: Result<FileObjects, quick_xml::DeError> = quick_xml::de::from_str(&text);
-                            match creatures_de {
-                                Ok(creatures) => {
-                                    for creature in creatures.objects {
-                                        match creature.Obj {
-                                            Some(obj) => {
-                                                let creature_key = obj.href.as_ref().unwrap()
-                                                    .replace("#xpointer(/Creature)", "")
-                                                    .trim_start_matches("/")
-                                                    .to_lowercase();
-                                                let creature_entity = files.get(&creature_key);
-                                                match creature_entity {
-                                                    Some(entity) => {
-                                                        collected_files.push((creature_key.clone(), entity.clone()));
-                                                    },
-                                                    None => println!("Key {} is not in files", &creature_key)
-                                                }
-                                            }
-                                            None => {}
-                                        }
-                                    }
-                                },
-                                Err(e) => println!("Error deserializing creatures.xdb, {}", e.to_string())
-                            }
-                        }
-                        _=> {}
-                    }
-                }
-                _ => ()
+/// Creature together with relationships resolved from other creatures.
+/// Upgrades of base creature are listed in order of `Upgrades`, so in ToE the second one is alternative upgrade.
+#[derive(Debug, Serialize)]
+pub struct Creature {
+    #[serde(flatten)]
+    pub shared: AdvMapCreatureShared,
+    /// Id of creature in creatures reftable.
+    pub id: Option<String>,
+    /// Base creature of upgrade.
+    pub base: Option<String>,
+    /// Upgrades of base creature.
+    pub upgrades: Option<Vec<String>>,
+    /// Other upgrade of the same base creature.
+    pub alternative: Option<String>,
+    /// `Abilities` with their texts and icons, abilities missing in reftable have only id.
+    pub resolved_abilities: Option<Vec<AbilityShared>>
+}
+
+impl Deref for Creature {
+    type Target = AdvMapCreatureShared;
+
+    fn deref(&self) -> &AdvMapCreatureShared {
+        &self.shared
+    }
+}
+
+/// Links between creatures, `Upgrades` are not read by `AdvMapCreatureShared`.
+#[derive(Debug, Default, Deserialize)]
+#[allow(non_snake_case)]
+struct CreatureLinks {
+    BaseCreature: Option<String>,
+    Upgrades: Option<Items<String>>
+}
+
+/// Power of creature, used to value armies.
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+struct CreaturePower {
+    Power: u64
+}
+
+/// Power of every creature in creatures reftable by its id.
+pub fn creature_powers(files: &HashMap<String, FileStructure>) -> HashMap<String, u64> {
+    read_reftable(files, CREATURES_REFTABLE).into_iter()
+        .filter_map(|(id, key)| {
+            let creature = deserialize_element::<CreaturePower>(&files.get(&key?)?.content, "Creature")?.ok()?;
+            Some((id, creature.Power))
+        })
+        .collect()
+}
+
+fn is_creature_id(id: &str) -> bool {
+    !id.is_empty() && id != "CREATURE_UNKNOWN"
+}
+
+fn creature_links(content: &str) -> CreatureLinks {
+    deserialize_element::<CreatureLinks>(content, "Creature")
+        .and_then(Result::ok)
+        .unwrap_or_default()
+}
+
+fn upgrades_of(links: &CreatureLinks) -> Vec<String> {
+    links.Upgrades.iter()
+        .flat_map(|upgrades| upgrades.items.iter().flatten())
+        .filter(|upgrade| is_creature_id(upgrade))
+        .cloned()
+        .collect()
+}
+
+/// Creatures reftable with links of every creature and abilities reftable, read once per scan.
+pub struct CreatureTables {
+    /// Ids of creatures by keys of their files.
+    ids: HashMap<String, String>,
+    /// Links of creatures by their ids.
+    links: HashMap<String, CreatureLinks>,
+    abilities: HashMap<String, AbilityShared>
+}
+
+impl CreatureTables {
+    fn read(files: &HashMap<String, FileStructure>) -> Self {
+        let mut tables = CreatureTables { ids: HashMap::new(), links: HashMap::new(), abilities: read_abilities(files) };
+        for (id, key) in read_reftable(files, CREATURES_REFTABLE) {
+            let Some(key) = key else { continue };
+            if let Some(file) = files.get(&key) {
+                tables.links.entry(id.clone()).or_insert_with(|| creature_links(&file.content));
             }
-            buf.clear();
+            tables.ids.entry(key).or_insert(id);
         }
+        tables
     }
 }
 
-// impl ToJson for AdvMapCreatureShared {
-// }
+pub struct CreatureFileCollector {}
 
-impl Output for AdvMapCreatureShared {
-    type ID = u16;
-    fn to_lua(&self, id: Option<u16>) -> String {
-        let is_generatable = if self.SubjectOfRandomGeneration == true {"1"} else {"nil"};
-        let is_flying = if self.Flying == true {"1"} else {"nil"};
-        let is_upgrade = if self.Upgrade == true {"1"} else {"nil"};
-        let mut abilities_string = String::new();
-        match &self.Abilities.Abilities {
-            Some(abilities) => {
-                for ability in abilities {
-                    abilities_string += &format!("{}, ", &ability);
+impl CollectFiles for CreatureFileCollector {
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        let creatures_xdb = match files.get(&CREATURES_REFTABLE.to_lowercase()) {
+            Some(reftable) => reftable,
+            None => {
+                crate::diagnostic!("Reftable {} is not in files", CREATURES_REFTABLE);
+                return;
+            }
+        };
+        match deserialize_element::<FileObjects>(&creatures_xdb.content, "objects") {
+            Some(Ok(creatures)) => {
+                for href in creatures.objects.into_iter().filter_map(|creature| creature.Obj?.href) {
+                    let creature_key = href
+                        .replace("#xpointer(/Creature)", "")
+                        .trim_start_matches('/')
+                        .to_lowercase();
+                    match files.get_key_value(&creature_key) {
+                        Some((key, entity)) => collected_files.push(CollectedFile::new(key, entity)),
+                        None => crate::diagnostic!("Key {} is not in files", &creature_key)
+                    }
                 }
             },
-            None => {}
+            Some(Err(e)) => crate::diagnostic!("Error deserializing creatures.xdb, {}", e),
+            None => ()
         }
-        let mut spells_string = String::new();
-        match &self.KnownSpells.spells {
-            Some(spells) => {
-                for spell in spells {
-                    spells_string += &format!("[{}] = {}, ", &spell.Spell, &spell.Mastery);
-                }
-            },
-            None => {}
-
-        }
-        format!(
-            "\t[{}] = {{
-        is_generatable = {},
-        attack = {},
-        defence = {},
-        dmg_min = {},
-        dmg_max = {},
-        speed = {},
-        ini = {},
-        health = {},
-        sp = {},
-        size = {},
-        exp = {},
-        power = {},
-        town = {},
-        first_element = {},
-        second_element = {},
-        grow = {},
-        tier = {},
-        cost = {},
-        range = {},
-        name = \"{}\",
-        desc = \"{}\",
-        icon = \"{}\",
-        is_flying = {},
-        abilities = {{{}}},
-        known_spells = {{{}}},
-        is_upgrade = {}
-    }},\n", 
-            id.unwrap() - 1,
-            is_generatable, 
-            self.AttackSkill, 
-            self.DefenceSkill, 
-            self.MinDamage, 
-            self.MaxDamage,
-            self.Speed,
-            self.Initiative,
-            self.Health,
-            self.SpellPoints,
-            self.CombatSize,
-            self.Exp,
-            self.Power,
-            self.CreatureTown,
-            self.MagicElement.First,
-            self.MagicElement.Second,
-            self.WeeklyGrowth,
-            self.CreatureTier,
-            self.Cost.Gold,
-            self.Range,
-            self.VisualExplained.as_ref().unwrap().CreatureNameFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.VisualExplained.as_ref().unwrap().DescriptionFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.VisualExplained.as_ref().unwrap().Icon128.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            is_flying,
-            abilities_string,
-            spells_string,
-            is_upgrade
-        )
     }
+}
 
-    fn to_json(&self) -> String {
-        serde_json::to_string_pretty(self).unwrap()
-    }
+impl_output! {
+    Creature as creature, ID = u16, key(id) = value id.unwrap() - 1, identity = creature.id.clone();
+    is_generatable: bool = creature.SubjectOfRandomGeneration,
+    attack: value = creature.AttackSkill,
+    defence: value = creature.DefenceSkill,
+    dmg_min: value = creature.MinDamage,
+    dmg_max: value = creature.MaxDamage,
+    speed: value = creature.Speed,
+    ini: value = creature.Initiative,
+    health: value = creature.Health,
+    sp: value = creature.SpellPoints,
+    size: value = creature.CombatSize,
+    exp: value = creature.Exp,
+    power: value = creature.Power,
+    town: value = creature.CreatureTown,
+    first_element: value = creature.MagicElement.First,
+    second_element: value = creature.MagicElement.Second,
+    grow: value = creature.WeeklyGrowth,
+    tier: value = creature.CreatureTier,
+    cost: value = creature.Cost.Gold,
+    range: value = creature.Range,
+    name: href = creature.VisualExplained.as_ref().and_then(|visual| visual.CreatureNameFileRef.as_ref()),
+    desc: href = creature.VisualExplained.as_ref().and_then(|visual| visual.DescriptionFileRef.as_ref()),
+    icon: href = creature.VisualExplained.as_ref().and_then(|visual| visual.Icon128.as_ref()),
+    is_flying: bool = creature.Flying,
+    abilities: list = creature.Abilities.Abilities,
+    known_spells: list = creature.KnownSpells.spells => |spell| format!("[{}] = {}", spell.Spell, spell.Mastery),
+    is_upgrade: bool = creature.Upgrade,
+    base: value = creature.base.as_deref().unwrap_or("nil"),
+    upgrades: list = creature.upgrades,
+    alternative: value = creature.alternative.as_deref().unwrap_or("nil"),
+    resolved_abilities: list = creature.resolved_abilities => |ability| ability.to_lua_inline()
 }
 
 pub struct CreatureScaner {
-    pub id: u16
+    pub id: u16,
+    pub tables: RunCache<CreatureTables>
 }
 
 impl CreatureScaner {
-    fn check_visual(&self, file_key: &String, content: &String, files: &HashMap<String, FileStructure>) -> Option<CreatureVisual> {
-        let mut buf = Vec::new();
-        let mut reader = Reader::from_str(&content);
-        reader.trim_text(true);
-        reader.expand_empty_elements(true);
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                Ok(Event::Eof) => break None,
-                Ok(Event::Start(e)) => {
-                    match e.name().as_ref() {
-                        b"CreatureVisual" => {
-                            let end = e.to_end().into_owned();
-                            let possible_text = reader.read_text(end.name());
-                            match possible_text {
-                                Ok(text) => {
-                                    let text = text.to_string();
-                                    let de_res: Result<CreatureVisual, quick_xml::DeError> = quick_xml::de::from_str(&format!("<CreatureVisual>{}</CreatureVisual>", text));
-                                    match de_res {
-                                        Ok(visual) => {
-                                            let name = configure_path(visual.CreatureNameFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let desc = configure_path(visual.DescriptionFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let icon_key = visual.Icon128.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()).replace("#xpointer(/Texture)", "");
-                                            let icon = configure_path(Some(&icon_key), file_key, files);
-                                            break Some(CreatureVisual { 
-                                                CreatureNameFileRef: Some(FileRef { href: Some(name) }), 
-                                                DescriptionFileRef: Some(FileRef { href: Some(desc) }), 
-                                                Icon128: Some(FileRef { href: Some(icon) }) 
-                                            })
-                                        }
-                                        Err(e) => {
-                                            println!("error while deserializing file key {}, {:?}", file_key, e.to_string());
-                                        }
-                                    }
-                                },
-                                Err(_e) => println!("error reading file content: {}", file_key)
-                            }
-                        }
-                        _=> {}
-                    }
-                }
-                _ => ()
+    fn check_visual(&self, file_key: &str, content: &str, files: &HashMap<String, FileStructure>) -> Option<CreatureVisual> {
+        match deserialize_element::<CreatureVisual>(content, "CreatureVisual")? {
+            Ok(mut visual) => {
+                resolve_file_ref(&mut visual.CreatureNameFileRef, file_key, files);
+                resolve_file_ref(&mut visual.DescriptionFileRef, file_key, files);
+                resolve_file_ref(&mut visual.Icon128, file_key, files);
+                Some(visual)
+            }
+            Err(e) => {
+                crate::diagnostic!("error while deserializing file key {}, {:?}", file_key, e.to_string());
+                None
             }
-            buf.clear();
+        }
+    }
+}
+
+impl CreatureScaner {
+    /// Finds base, upgrades and alternative upgrade of creature using creatures reftable, and its abilities.
+    fn resolve_links(&self, creature: AdvMapCreatureShared, file_key: &str, content: &str, files: &HashMap<String, FileStructure>) -> Creature {
+        let tables = self.tables.get_or_init(|| CreatureTables::read(files));
+        let id = tables.ids.get(file_key).cloned();
+        let parsed_links;
+        let links = match id.as_ref().and_then(|id| tables.links.get(id)) {
+            Some(links) => links,
+            None => {
+                parsed_links = creature_links(content);
+                &parsed_links
+            }
+        };
+        let base = links.BaseCreature.clone()
+            .filter(|base| is_creature_id(base) && Some(base) != id.as_ref());
+        let upgrades = upgrades_of(links);
+        let alternative = base.as_ref()
+            .and_then(|base| tables.links.get(base))
+            .and_then(|base_links| upgrades_of(base_links).into_iter().find(|upgrade| Some(upgrade) != id.as_ref()));
+        let resolved_abilities = creature.Abilities.Abilities.as_ref().map(|ids| ids.iter()
+            .map(|id| tables.abilities.get(id)
+                .cloned()
+                .unwrap_or_else(|| AbilityShared { ID: id.clone(), ..Default::default() }))
+            .collect());
+        Creature {
+            shared: creature,
+            id,
+            base,
+            upgrades: (!upgrades.is_empty()).then_some(upgrades),
+            alternative,
+            resolved_abilities
         }
     }
 }
 
 impl Scan<u16> for CreatureScaner {
-    fn scan(&mut self, file_key: &String, entity: &String, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
-        let mut buf = Vec::new();
-        let mut reader = Reader::from_str(entity);
-        reader.trim_text(true);
-        reader.expand_empty_elements(true);
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                Ok(Event::Eof) => break None,
-                Ok(Event::Start(e)) => {
-                    match e.name().as_ref() {
-                        b"Creature" => {
-                            let end = e.to_end().into_owned();
-                            let possible_text = reader.read_text(end.name());
-                            match possible_text {
-                                Ok(text) => {
-                                    let text = text.to_string();
-                                    let de_res: Result<AdvMapCreatureShared, quick_xml::DeError> = quick_xml::de::from_str(
-                                        &format!("<Creature>{}</Creature>", text)
-                                    );
-                                    match de_res {
-                                        Ok(mut creature) => {
-                                            //println!("Creature scanned: {:?}", &creature);
-                                            let visual = creature.Visual.as_ref();
-                                            match visual {
-                                                Some(actual_visual) => {
-                                                    let visual_key = actual_visual.href.as_ref().unwrap()
-                                                        .replace("#xpointer(/CreatureVisual)", "")
-                                                        .trim_start_matches("/")
-                                                        .to_lowercase();
-                                                    println!("visual key: {}", &visual_key);
-                                                    let actual_visual_key = configure_path(Some(&visual_key), file_key, files);
-                                                    let visual_file = files.get(&actual_visual_key);
-                                                    match visual_file {
-                                                        Some(actual_visual_file) => {
-                                                            let visual_checked = self.check_visual(&actual_visual_key, &actual_visual_file.content, files);
-                                                            creature.VisualExplained = visual_checked;
-                                                        },
-                                                        None => println!("Can't find visual of {}", &actual_visual_key)
-                                                    }
-                                                },
-                                                None => {}
-                                            }
-                                            //println!("Creature's visual: {:?}", &creature.VisualExplained);
-                                            self.id+=1;
-                                            break Some(Box::new(creature));
-                                        }
-                                        Err(e) => {
-                                            println!("error while deserializing file key {}, {:?}", file_key, e.to_string());
-                                        }
-                                    }
-                                },
-                                Err(_e) => println!("error reading file content: {}", file_key)
-                            }
-                        }
-                        _=> {}
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
+        match deserialize_element::<AdvMapCreatureShared>(entity, "Creature")? {
+            Ok(mut creature) => {
+                if let Some(visual_href) = creature.Visual.as_ref().and_then(|visual| visual.href.as_ref()) {
+                    let visual_key = visual_href
+                        .replace("#xpointer(/CreatureVisual)", "")
+                        .trim_start_matches('/')
+                        .to_lowercase();
+                    let actual_visual_key = configure_path(Some(&visual_key), file_key, files);
+                    match files.get(&actual_visual_key) {
+                        Some(actual_visual_file) => {
+                            creature.VisualExplained = self.check_visual(&actual_visual_key, &actual_visual_file.content, files);
+                        },
+                        None => crate::diagnostic!("Can't find visual of {}", &actual_visual_key)
                     }
                 }
-                _ => ()
+                Some(Box::new(self.resolve_links(creature, file_key, entity, files)))
+            }
+            Err(e) => {
+                crate::diagnostic!("error while deserializing file key {}, {:?}", file_key, e.to_string());
+                None
             }
-            buf.clear();
         }
     }
 
-    fn get_id(&self) -> Option<u16> {
-        Some(self.id)
+    fn get_id(&self, position: usize) -> Option<u16> {
+        Some(self.id + position as u16)
+    }
+
+    fn prepare(&self) {
+        self.tables.clear();
     }
-}
\ No newline at end of file
+}
+/// Base creature of tier with its upgrade and alternative upgrade.
+#[derive(Debug, Clone, Serialize)]
+pub struct TierChain {
+    pub base: String,
+    pub upgrade: Option<String>,
+    pub alternative: Option<String>
+}
+
+/// Base creatures of every faction by tier.
+pub fn creature_lineup(creatures: &[ScannedEntity<u16>]) -> BTreeMap<String, BTreeMap<u8, Vec<TierChain>>> {
+    let mut lineup: BTreeMap<String, BTreeMap<u8, Vec<TierChain>>> = BTreeMap::new();
+    for creature in creatures.iter().filter_map(|creature| creature.get::<Creature>()).filter(|creature| !creature.Upgrade) {
+        let base = match creature.id.as_ref() {
+            Some(id) => id.clone(),
+            None => continue
+        };
+        let mut upgrades = creature.upgrades.iter().flatten().cloned();
+        lineup.entry(creature.CreatureTown.clone())
+            .or_default()
+            .entry(creature.CreatureTier)
+            .or_default()
+            .push(TierChain { base, upgrade: upgrades.next(), alternative: upgrades.next() });
+    }
+    lineup
+}
+
+/// Exports creature lineup of factions as a table of its own.
+pub struct CreatureLineupExport {}
+
+impl Export for CreatureLineupExport {
+    fn table_name(&self) -> &str {
+        "MCCS_CREATURE_LINEUP"
+    }
+
+    fn output_file_name(&self) -> &str {
+        "creature_lineup.lua"
+    }
+
+    fn export(&self, files: &Vfs, scans: &ScanCache) -> ExportedTable {
+        let creatures = scans.scan(&creature_processor(), files);
+        let lineup = creature_lineup(&creatures.entities);
+        let mut lua = format!("{} = {{\n", self.table_name());
+        for (town, tiers) in &lineup {
+            let tiers: Vec<(String, String)> = tiers.iter()
+                .map(|(tier, chains)| (format!("[{}]", tier), lua_list(chains, |chain| format!(
+                    "{{base = {}, upgrade = {}, alternative = {}}}",
+                    chain.base, chain.upgrade.as_deref().unwrap_or("nil"), chain.alternative.as_deref().unwrap_or("nil")
+                ))))
+                .collect();
+            let tiers: Vec<(&str, String)> = tiers.iter().map(|(tier, chains)| (tier.as_str(), chains.clone())).collect();
+            lua += &lua_entry(town, &tiers);
+        }
+        lua.push('}');
+        ExportedTable {
+            collected: creatures.collected,
+            scanned: creatures.entities.len(),
+            lua,
+            json: serde_json::to_string_pretty(&lineup).unwrap(),
+            diagnostics: vec![]
+        }
+    }
+}
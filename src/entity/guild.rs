@@ -0,0 +1,79 @@
+//! Spells mage guild of every faction can offer, levels of guild restrict schools and number of spells.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+use super::{spell::{Spell, SpellKind}, spell_processor, town::TownTypeShared, town_processor, ScannedEntity};
+use crate::{export::{Export, ExportedTable, ScanCache}, output::{lua_entry, lua_list}, pak::Vfs};
+
+/// Spells of one guild level, `spells` are all spells that may appear there.
+#[derive(Debug, Clone, Serialize)]
+pub struct GuildLevelSpells {
+    pub level: u8,
+    pub spells_count: u8,
+    pub schools: Vec<String>,
+    pub spells: Vec<String>
+}
+
+/// Guild levels of every faction, spells of level are ordinary spells of the same level from its schools.
+pub fn guild_spells(towns: &[ScannedEntity<String>], spells: &[&Spell]) -> BTreeMap<String, Vec<GuildLevelSpells>> {
+    let mut guilds = BTreeMap::new();
+    for town in towns.iter().filter_map(|town| town.get::<TownTypeShared>()) {
+        let levels = town.MagicGuild.items.iter().flatten().enumerate()
+            .map(|(index, guild_level)| {
+                let level = index as u8 + 1;
+                let schools: Vec<String> = guild_level.Schools.items.iter().flatten().cloned().collect();
+                let level_spells: Vec<String> = spells.iter()
+                    .filter(|spell| spell.kind == SpellKind::Spell && spell.Level == level && schools.contains(&spell.MagicSchool))
+                    .map(|spell| spell.id.clone())
+                    .collect();
+                if level_spells.len() < guild_level.SpellsCount as usize {
+                    crate::diagnostic!("Level {} of {} guild offers {} spells, but only {} can appear there", level, town.Town, guild_level.SpellsCount, level_spells.len());
+                }
+                GuildLevelSpells { level, spells_count: guild_level.SpellsCount, schools, spells: level_spells }
+            })
+            .collect();
+        guilds.insert(town.Town.clone(), levels);
+    }
+    guilds
+}
+
+/// Exports mage guild spells of factions as a table of its own.
+pub struct MagicGuildExport {}
+
+impl Export for MagicGuildExport {
+    fn table_name(&self) -> &str {
+        "MCCS_MAGIC_GUILDS"
+    }
+
+    fn output_file_name(&self) -> &str {
+        "magic_guilds.lua"
+    }
+
+    fn export(&self, files: &Vfs, scans: &ScanCache) -> ExportedTable {
+        let towns = scans.scan(&town_processor(), files);
+        let spells = scans.scan(&spell_processor(), files);
+        let spells: Vec<&Spell> = spells.entities.iter().filter_map(|spell| spell.get::<Spell>()).collect();
+        let guilds = guild_spells(&towns.entities, &spells);
+        let mut lua = format!("{} = {{\n", self.table_name());
+        for (town, levels) in &guilds {
+            let levels: Vec<(String, String)> = levels.iter()
+                .map(|level| (format!("[{}]", level.level), format!(
+                    "{{count = {}, schools = {}, spells = {}}}",
+                    level.spells_count,
+                    lua_list(&level.schools, |school| school.to_string()),
+                    lua_list(&level.spells, |spell| spell.to_string())
+                )))
+                .collect();
+            let levels: Vec<(&str, String)> = levels.iter().map(|(level, spells)| (level.as_str(), spells.clone())).collect();
+            lua += &lua_entry(town, &levels);
+        }
+        lua.push('}');
+        ExportedTable {
+            collected: towns.collected,
+            scanned: towns.entities.len(),
+            lua,
+            json: serde_json::to_string_pretty(&guilds).unwrap(),
+            diagnostics: vec![]
+        }
+    }
+}
@@ -1,127 +1,205 @@
 use crate::{
-    entity::{Scan, Output, configure_path, CollectFiles},
-    pak::FileStructure
+    entity::{Scan, Output, deserialize_element, resolve_file_ref, CollectFiles, CollectedFile, Items, RunCache},
+    impl_output,
+    pak::{FileStructure, Vfs}
 };
-use quick_xml::{Reader, events::Event};
-use std::collections::HashMap;
-use homm5_types::{common::FileRef, hero::AdvMapHeroShared};
+use std::{collections::HashMap, ops::Deref};
+use serde::{Serialize, Deserialize};
+use homm5_types::{common::{ArmySlot, FileRef, SkillMastery}, hero::{AdvMapHeroShared, Editable, Skill}};
+use super::hero_class::{read_hero_classes, HeroClassShared};
 
-impl Output for AdvMapHeroShared {
-    type ID = String;
-    fn to_lua(&self, _id: Option<String>) -> String {
-        let is_scenario_lua = if self.ScenarioHero == true {"1"} else {"nil"};
-        format!(
-            "\t[\"{}\"] = {{
-        is_scenario = {},
-        hero_class = {},
-        spec = {},
-        spec_name = \"{}\",
-        spec_desc = \"{}\",
-        spec_icon = \"{}\",
-        icon = \"{}\",
-        town = {},
-        name = \"{}\",
-        bio = \"{}\"
-    }},\n", 
-            self.InternalName, 
-            is_scenario_lua, 
-            self.Class, 
-            self.Specialization, 
-            self.SpecializationNameFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()), 
-            self.SpecializationDescFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()), 
-            self.SpecializationIcon.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()), 
-            self.FaceTexture.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.TownType,
-            self.Editable.NameFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.Editable.BiographyFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new())
-        )
+/// Parts of `Editable` hero starts with, not read by `AdvMapHeroShared`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct HeroStart {
+    pub skills: Items<SkillMastery>,
+    pub perkIDs: Items<String>,
+    pub spellIDs: Items<String>,
+    #[serde(alias = "armySlots")]
+    pub Army: Items<ArmySlot>,
+    #[serde(alias = "artifactIDs")]
+    pub Artifacts: Items<String>
+}
+
+/// `<Editable>` element read in one pass, it is split into `Editable` and `HeroStart`.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EditableElement {
+    NameFileRef: Option<FileRef>,
+    BiographyFileRef: Option<FileRef>,
+    Offence: u16,
+    Defence: u16,
+    Spellpower: u16,
+    Knowledge: u16,
+    Ballista: bool,
+    FirstAidTent: bool,
+    AmmoCart: bool,
+    #[serde(default)]
+    skills: Items<SkillMastery>,
+    #[serde(default)]
+    perkIDs: Items<String>,
+    #[serde(default)]
+    spellIDs: Items<String>,
+    #[serde(default, alias = "armySlots")]
+    Army: Items<ArmySlot>,
+    #[serde(default, alias = "artifactIDs")]
+    Artifacts: Items<String>
+}
+
+/// `<AdvMapHeroShared>` element read in one pass, the same as `AdvMapHeroShared` except for `Editable`.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct HeroElement {
+    Model: Option<FileRef>,
+    AnimSet: Option<FileRef>,
+    WaterBased: bool,
+    ApplyHeroTrace: bool,
+    FlyPassable: bool,
+    Icon128: Option<String>,
+    InternalName: String,
+    Class: String,
+    Specialization: String,
+    PrimarySkill: Skill,
+    SpecializationNameFileRef: Option<FileRef>,
+    SpecializationDescFileRef: Option<FileRef>,
+    SpecializationIcon: Option<FileRef>,
+    FaceTexture: Option<FileRef>,
+    TownType: String,
+    Editable: EditableElement,
+    ScenarioHero: bool
+}
+
+impl HeroElement {
+    fn split(self) -> (AdvMapHeroShared, HeroStart) {
+        let editable = self.Editable;
+        let start = HeroStart {
+            skills: editable.skills,
+            perkIDs: editable.perkIDs,
+            spellIDs: editable.spellIDs,
+            Army: editable.Army,
+            Artifacts: editable.Artifacts
+        };
+        let shared = AdvMapHeroShared {
+            Model: self.Model,
+            AnimSet: self.AnimSet,
+            WaterBased: self.WaterBased,
+            ApplyHeroTrace: self.ApplyHeroTrace,
+            FlyPassable: self.FlyPassable,
+            Icon128: self.Icon128,
+            InternalName: self.InternalName,
+            Class: self.Class,
+            Specialization: self.Specialization,
+            PrimarySkill: self.PrimarySkill,
+            SpecializationNameFileRef: self.SpecializationNameFileRef,
+            SpecializationDescFileRef: self.SpecializationDescFileRef,
+            SpecializationIcon: self.SpecializationIcon,
+            FaceTexture: self.FaceTexture,
+            TownType: self.TownType,
+            Editable: Editable {
+                NameFileRef: editable.NameFileRef,
+                BiographyFileRef: editable.BiographyFileRef,
+                Offence: editable.Offence,
+                Defence: editable.Defence,
+                Spellpower: editable.Spellpower,
+                Knowledge: editable.Knowledge,
+                Ballista: editable.Ballista,
+                FirstAidTent: editable.FirstAidTent,
+                AmmoCart: editable.AmmoCart
+            },
+            ScenarioHero: self.ScenarioHero
+        };
+        (shared, start)
     }
+}
 
-    fn to_json(&self) -> String {
-        serde_json::to_string_pretty(self).unwrap()
+/// Hero together with data resolved from other files.
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct Hero {
+    #[serde(flatten)]
+    pub shared: AdvMapHeroShared,
+    /// Name of hero's class from `HeroClass.xdb`.
+    pub ClassNameFileRef: Option<FileRef>,
+    pub Start: HeroStart
+}
+
+impl Deref for Hero {
+    type Target = AdvMapHeroShared;
+
+    fn deref(&self) -> &AdvMapHeroShared {
+        &self.shared
     }
 }
 
+impl_output! {
+    Hero as hero, ID = String, key(_id) = string hero.InternalName, identity = Some(hero.InternalName.clone());
+    is_scenario: bool = hero.ScenarioHero,
+    // scripts look class up in MCCS_HERO_CLASSES themselves, so heroes.lua loads without hero_classes.lua
+    hero_class: value = hero.Class,
+    class_name: href = hero.ClassNameFileRef,
+    spec: value = hero.Specialization,
+    spec_name: href = hero.SpecializationNameFileRef,
+    spec_desc: href = hero.SpecializationDescFileRef,
+    spec_icon: href = hero.SpecializationIcon,
+    icon: href = hero.FaceTexture,
+    town: value = hero.TownType,
+    name: href = hero.Editable.NameFileRef,
+    bio: href = hero.Editable.BiographyFileRef,
+    attack: value = hero.Editable.Offence,
+    defence: value = hero.Editable.Defence,
+    spellpower: value = hero.Editable.Spellpower,
+    knowledge: value = hero.Editable.Knowledge,
+    skills: list = hero.Start.skills.items => |skill| format!("[{}] = {}", skill.skill_id, skill.mastery),
+    perks: list = hero.Start.perkIDs.items,
+    spells: list = hero.Start.spellIDs.items,
+    army: list = hero.Start.Army.items => |slot| format!("{{creature = {}, count = {}}}", slot.creature, slot.count),
+    artifacts: list = hero.Start.Artifacts.items
+}
+
 pub struct HeroFileCollector {}
 
 impl CollectFiles for HeroFileCollector {
-    fn collect(&self, files: &HashMap<String, FileStructure>, collected_files: &mut Vec<(String, FileStructure)>) {
-        files.iter()
-            .filter(|f| {
-                f.1.content.contains("AdvMapHeroShared") && f.1.content.contains("ScenarioHero")
-            })
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        files.files_with_root("AdvMapHeroShared")
             .for_each(|f| {
-                collected_files.push((f.0.clone(), f.1.clone()))
+                collected_files.push(CollectedFile::new(f.0, f.1))
             });
     }
 }
 
-pub struct HeroScaner {}
+pub struct HeroScaner {
+    /// Classes of `HeroClass.xdb` by their ids.
+    pub classes: RunCache<HashMap<String, HeroClassShared>>
+}
 
 impl Scan<String> for HeroScaner {
-    fn scan(&mut self, file_key: &String, entity: &String, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = String>>> {
-        let mut buf = Vec::new();
-        let mut reader = Reader::from_str(entity);
-        reader.trim_text(true);
-        reader.expand_empty_elements(true);
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                Ok(Event::Eof) => break None,
-                Ok(Event::Start(e)) => {
-                    match e.name().as_ref() {
-                        b"AdvMapHeroShared" => {
-                            let end = e.to_end().into_owned();
-                            let possible_text = reader.read_text(end.name());
-                            match possible_text {
-                                Ok(text) => {
-                                    let text = text.to_string();
-                                    let de_res: Result<AdvMapHeroShared, quick_xml::DeError> = quick_xml::de::from_str(&format!("<AdvMapHeroShared>{}</AdvMapHeroShared>", text));
-                                    match de_res {
-                                        Ok(mut hero) => {
-                                            let spec_name = configure_path(hero.SpecializationNameFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let spec_desc = configure_path(hero.SpecializationDescFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let spec_icon = configure_path(
-                                                Some(&hero.SpecializationIcon.as_ref().unwrap().href.as_ref()
-                                                    .unwrap_or(&String::new()).replace("#xpointer(/Texture)", "")), 
-                                                    file_key, 
-                                                    files
-                                            );
-                                            let icon = configure_path(
-                                                Some(&hero.FaceTexture.as_ref().unwrap().href.as_ref()
-                                                .unwrap_or(&String::new()).replace("#xpointer(/Texture)", "")), 
-                                                file_key, 
-                                                files
-                                            );
-                                            let name = configure_path(hero.Editable.NameFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let bio = configure_path(hero.Editable.BiographyFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            hero.SpecializationNameFileRef = Some(FileRef { href: Some(spec_name) });
-                                            hero.SpecializationDescFileRef = Some(FileRef { href: Some(spec_desc) });
-                                            hero.SpecializationIcon = Some(FileRef { href: Some(spec_icon) });
-                                            hero.FaceTexture = Some(FileRef { href: Some(icon) });
-                                            hero.Editable.NameFileRef = Some(FileRef { href: Some(name) });
-                                            hero.Editable.BiographyFileRef = Some(FileRef { href: Some(bio) });
-                                            break Some(Box::new(hero));
-                                        }
-                                        Err(e) => {
-                                            println!("error while deserializing {:?}", e.to_string());
-                                        }
-                                    }
-                                },
-                                Err(e) => println!("error reading file content: {}, {}", file_key, e.to_string())
-                            }
-                        }
-                        _=> {}
-                    }
-                }
-                _ => ()
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = String>>> {
+        match deserialize_element::<HeroElement>(entity, "AdvMapHeroShared")? {
+            Ok(element) => {
+                let (mut hero, start) = element.split();
+                resolve_file_ref(&mut hero.SpecializationNameFileRef, file_key, files);
+                resolve_file_ref(&mut hero.SpecializationDescFileRef, file_key, files);
+                resolve_file_ref(&mut hero.SpecializationIcon, file_key, files);
+                resolve_file_ref(&mut hero.FaceTexture, file_key, files);
+                resolve_file_ref(&mut hero.Editable.NameFileRef, file_key, files);
+                resolve_file_ref(&mut hero.Editable.BiographyFileRef, file_key, files);
+                let classes = self.classes.get_or_init(|| read_hero_classes(files));
+                let class_name = classes.get(&hero.Class).and_then(|class| class.NameFileRef.clone());
+                Some(Box::new(Hero { shared: hero, ClassNameFileRef: class_name, Start: start }))
+            }
+            Err(e) => {
+                crate::diagnostic!("error while deserializing {}, {:?}", file_key, e.to_string());
+                None
             }
-            buf.clear();
         }
     }
 
-    fn get_id(&self) -> Option<String> {
+    fn get_id(&self, _position: usize) -> Option<String> {
         None
     }
 
+    fn prepare(&self) {
+        self.classes.clear();
+    }
 }
\ No newline at end of file
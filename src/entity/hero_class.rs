@@ -0,0 +1,81 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::common::FileRef;
+use super::{configure_path, read_reftable_entities, FileStructure, Items, ReftableEntity};
+use crate::impl_output;
+
+pub const HERO_CLASSES_REFTABLE: &str = "gamemechanics/reftables/heroclass.xdb";
+
+/// Chances(in percents) to get primary skill on level up.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AttributeProbs {
+    pub OffenceProb: u16,
+    pub DefenceProb: u16,
+    pub SpellpowerProb: u16,
+    pub KnowledgeProb: u16
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SkillProb {
+    pub SkillID: String,
+    pub Prob: u16
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct HeroClassShared {
+    /// Id of reftable item, not a part of class element.
+    #[serde(default)]
+    pub ID: String,
+    pub NameFileRef: Option<FileRef>,
+    #[serde(default)]
+    pub AttributeProbs: AttributeProbs,
+    /// Weights of skills offered on level up, skills with zero weight are never offered.
+    #[serde(default)]
+    pub SkillsProbs: Items<SkillProb>,
+    #[serde(default, alias = "TownType")]
+    pub Town: Option<String>
+}
+
+impl HeroClassShared {
+    pub fn allowed_skills(&self) -> Vec<&String> {
+        self.SkillsProbs.items.iter().flatten().filter(|skill| skill.Prob > 0).map(|skill| &skill.SkillID).collect()
+    }
+
+    pub fn forbidden_skills(&self) -> Vec<&String> {
+        self.SkillsProbs.items.iter().flatten().filter(|skill| skill.Prob == 0).map(|skill| &skill.SkillID).collect()
+    }
+}
+
+impl_output! {
+    HeroClassShared as class, ID = String, key(_id) = value class.ID, identity = Some(class.ID.clone());
+    name: href = class.NameFileRef,
+    town: value = class.Town.as_deref().unwrap_or("nil"),
+    attack_chance: value = class.AttributeProbs.OffenceProb,
+    defence_chance: value = class.AttributeProbs.DefenceProb,
+    spellpower_chance: value = class.AttributeProbs.SpellpowerProb,
+    knowledge_chance: value = class.AttributeProbs.KnowledgeProb,
+    skill_chances: list = class.SkillsProbs.items => |skill| format!("[{}] = {}", skill.SkillID, skill.Prob),
+    allowed_skills: list = Some(class.allowed_skills()),
+    forbidden_skills: list = Some(class.forbidden_skills())
+}
+
+impl ReftableEntity for HeroClassShared {
+    const NAME: &'static str = "hero class";
+
+    fn complete(mut self, id: String, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<Self> {
+        self.ID = id;
+        let name = self.NameFileRef.as_ref().and_then(|name| name.href.as_ref());
+        self.NameFileRef = Some(FileRef { href: Some(configure_path(name, file_key, files)) });
+        Some(self)
+    }
+}
+
+/// All classes of `HeroClass.xdb` by their ids.
+pub fn read_hero_classes(files: &HashMap<String, FileStructure>) -> HashMap<String, HeroClassShared> {
+    read_reftable_entities::<HeroClassShared>(files, HERO_CLASSES_REFTABLE).into_iter()
+        .map(|class| (class.ID.clone(), class))
+        .collect()
+}
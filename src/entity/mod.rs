@@ -2,11 +2,35 @@ pub mod hero;
 pub mod creature;
 pub mod art;
 pub mod spell;
+pub mod configured;
+pub mod skill;
+pub mod hero_class;
+pub mod town;
+pub mod ability;
+pub mod art_set;
+pub mod adv_object;
+pub mod bank;
+pub mod rules;
+pub mod guild;
 
-use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
+use std::{any::Any, collections::HashMap, marker::PhantomData, sync::{Arc, Mutex}, thread};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use homm5_types::common::FileRef;
-use crate::pak::FileStructure;
+use quick_xml::{Reader, DeError, events::Event};
+use crate::{export::{capture, report}, pak::{FileStructure, Vfs}};
+use self::{
+    ability::{AbilityShared, ABILITIES_REFTABLE},
+    adv_object::{AdvObjectFileCollector, AdvObjectScaner},
+    art::{ArtFileCollector, ArtScaner},
+    art_set::{ArtifactSetShared, ARTIFACT_SETS_REFTABLE},
+    bank::{CreatureBankFileCollector, CreatureBankScaner},
+    creature::{CreatureFileCollector, CreatureScaner},
+    hero::{HeroFileCollector, HeroScaner},
+    hero_class::{HeroClassShared, HERO_CLASSES_REFTABLE},
+    skill::{SkillShared, SKILLS_REFTABLE},
+    town::{TownFileCollector, TownScaner},
+    spell::{SpellFileCollector, SpellScaner}
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(non_snake_case)]
@@ -21,19 +45,165 @@ pub struct FileObjects {
     pub objects: Vec<FileObject>
 }
 
+/// List of `<Item>` elements.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Items<T> {
+    #[serde(rename = "Item")]
+    pub items: Option<Vec<T>>
+}
+
+impl<T> Default for Items<T> {
+    fn default() -> Self {
+        Items { items: None }
+    }
+}
+
+/// Item of reftable `objects`, with entity inline or referenced by obj.
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ReftableItem<T> {
+    pub ID: String,
+    pub obj: Option<T>
+}
+
+/// File chosen by collector. Borrows from file map, so collecting copies nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct CollectedFile<'a> {
+    pub key: &'a str,
+    pub pak: &'a str,
+    /// Whole content of file or its part containing entity.
+    pub content: &'a str
+}
+
+impl<'a> CollectedFile<'a> {
+    pub fn new(key: &'a str, file: &'a FileStructure) -> Self {
+        CollectedFile { key, pak: &file.pak, content: &file.content }
+    }
+}
+
 pub trait CollectFiles {
-    fn collect(&self, files: &HashMap<String, FileStructure>, collected_files: &mut Vec<(String, FileStructure)>);
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>);
+}
+
+/// Collects every `<Item>` of reftable `objects` as slice of reftable, so scaner gets both ID and obj.
+pub struct ReftableItemCollector {
+    /// Key of reftable, like `gamemechanics/reftables/skills.xdb`.
+    pub reftable: String
+}
+
+impl CollectFiles for ReftableItemCollector {
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        let (reftable_key, reftable) = match files.get_key_value(&self.reftable) {
+            Some(reftable) => reftable,
+            None => {
+                crate::diagnostic!("Reftable {} is not in files", &self.reftable);
+                return;
+            }
+        };
+        let mut reader = Reader::from_str(&reftable.content);
+        let mut in_objects = false;
+        loop {
+            let start = reader.buffer_position();
+            match reader.read_event() {
+                Err(e) => {
+                    crate::diagnostic!("Error reading {} at position {}: {:?}", &self.reftable, reader.buffer_position(), e);
+                    break;
+                },
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) if e.name().as_ref() == b"objects" => in_objects = true,
+                Ok(Event::End(e)) if e.name().as_ref() == b"objects" => in_objects = false,
+                Ok(Event::Start(e)) if in_objects && e.name().as_ref() == b"Item" => {
+                    let end = e.to_end().into_owned();
+                    if reader.read_to_end(end.name()).is_err() {
+                        break;
+                    }
+                    collected_files.push(CollectedFile {
+                        key: reftable_key,
+                        pak: &reftable.pak,
+                        content: &reftable.content[start..reader.buffer_position()]
+                    });
+                },
+                _ => ()
+            }
+        }
+    }
+}
+
+/// Entity kept in item of reftable, its id is the ID of item.
+pub trait ReftableEntity: DeserializeOwned + Output<ID = String> {
+    /// What entity is, for messages.
+    const NAME: &'static str;
+    /// Completes entity read from item with given id, like resolving its references. `None` skips it.
+    fn complete(self, id: String, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<Self>;
+}
+
+/// Scans items collected by `ReftableItemCollector` into entities of type `E`.
+pub struct ReftableItemScaner<E> {
+    entity: PhantomData<fn() -> E>
+}
+
+impl<E> Default for ReftableItemScaner<E> {
+    fn default() -> Self {
+        ReftableItemScaner { entity: PhantomData }
+    }
+}
+
+impl<E: ReftableEntity> Scan<String> for ReftableItemScaner<E> {
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = String>>> {
+        match deserialize_element::<ReftableItem<E>>(entity, "Item")? {
+            Ok(item) => Some(Box::new(item.obj?.complete(item.ID, file_key, files)?)),
+            Err(e) => {
+                crate::diagnostic!("error while deserializing {} of {}, {:?}", E::NAME, file_key, e.to_string());
+                None
+            }
+        }
+    }
+
+    fn get_id(&self, _position: usize) -> Option<String> {
+        None
+    }
 }
 
-pub trait Scan<T> {
-    fn scan(&mut self, file_key: &String, entity: &String, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = T>>>;
-    fn get_id(&self) -> Option<T>;
+/// Scaners parse every file on its own, so files can be scanned in parallel.
+pub trait Scan<T>: Send + Sync {
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = T>>>;
+    /// Id of entity that was scanned successfully `position`-th(starting from 1) in order of collected files.
+    fn get_id(&self, position: usize) -> Option<T>;
+    /// Called before files are scanned, scaners drop data cached by previous scan here.
+    fn prepare(&self) {}
 }
 
-pub trait Output {
+/// Data scaner needs for every file, built once by the first file scanned.
+/// Files of other scan may differ, so it is cleared in `Scan::prepare`.
+pub struct RunCache<C> {
+    value: Mutex<Option<Arc<C>>>
+}
+
+impl<C> Default for RunCache<C> {
+    fn default() -> Self {
+        RunCache { value: Mutex::new(None) }
+    }
+}
+
+impl<C> RunCache<C> {
+    pub fn get_or_init(&self, init: impl FnOnce() -> C) -> Arc<C> {
+        self.value.lock().unwrap().get_or_insert_with(|| Arc::new(init())).clone()
+    }
+
+    pub fn clear(&self) {
+        *self.value.lock().unwrap() = None;
+    }
+}
+
+pub trait Output: Any + Send {
     type ID;
     fn to_lua(&self, id: Option<Self::ID>) -> String;
     fn to_json(&self) -> String;
+    /// Identity of entity that doesn't depend on order of scanned files, like its reftable id.
+    /// Entities of different game states are matched by it, entities without identity are matched by file key.
+    fn identity(&self) -> Option<String> {
+        None
+    }
 }
 
 pub struct ScanProcessor<T> {
@@ -54,48 +224,283 @@ impl<T> ScanProcessor<T> {
     }
 }
 
-impl<T> ScanProcessor<T> {
-    pub fn run(&mut self, files: &HashMap<String, FileStructure>) -> (String, String) {
+/// Entity produced by a scaner, together with its id and the file it was read from.
+pub struct ScannedEntity<T> {
+    pub id: Option<T>,
+    pub file_key: String,
+    pub pak: String,
+    pub entity: Box<dyn Output<ID = T>>
+}
+
+impl<T: 'static> ScannedEntity<T> {
+    /// Returns scanned entity as concrete struct, if it is of type `E`.
+    pub fn get<E: Any>(&self) -> Option<&E> {
+        let entity: &dyn Any = self.entity.as_ref();
+        entity.downcast_ref::<E>()
+    }
+}
+
+impl<T: Clone + 'static> ScanProcessor<T> {
+    /// Collects and scans files, returning entities instead of their text representation.
+    /// Files are scanned in parallel, but entities and their ids are in order of collected files.
+    pub fn scan(&self, files: &Vfs) -> Vec<ScannedEntity<T>> {
+        let actual_files = self.collect(files);
+        self.scan_collected(&actual_files, files)
+    }
+
+    pub fn collect<'a>(&self, files: &'a Vfs) -> Vec<CollectedFile<'a>> {
         let mut actual_files = vec![];
         self.collector.collect(files, &mut actual_files);
-        println!("files collected: {:?}", &actual_files);
-        let mut output_string = format!("{} = {{\n", &self.table_name);
-        let mut json_string = String::from("[");
-        for file in actual_files {
-            let scanned_file = self.scaner.scan(&file.0, &file.1.content, files);
-            let id = self.scaner.get_id();
-            match scanned_file {
-                Some(actual_file) => {
-                    output_string += &actual_file.to_lua(id);
-                    json_string += &format!("{},\n", actual_file.to_json());
-                },
-                None => {}
+        actual_files
+    }
+
+    /// Scans files returned by `collect`.
+    pub fn scan_collected(&self, actual_files: &[CollectedFile], files: &Vfs) -> Vec<ScannedEntity<T>> {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        self.scan_in_chunks(actual_files, files, threads)
+    }
+
+    /// Scans files split into `chunks` parts, each one in its own thread.
+    fn scan_in_chunks(&self, actual_files: &[CollectedFile], files: &Vfs, chunks: usize) -> Vec<ScannedEntity<T>> {
+        let chunk_size = actual_files.len().div_ceil(chunks).max(1);
+        let scaner = self.scaner.as_ref();
+        scaner.prepare();
+        let scanned: Vec<Option<Box<dyn Output<ID = T>>>> = thread::scope(|scope| {
+            let handles: Vec<_> = actual_files.chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || capture(|| {
+                    chunk.iter()
+                        .map(|file| scaner.scan(file.key, file.content, files))
+                        .collect::<Vec<_>>()
+                })))
+                .collect();
+            // messages of every thread are reported on this one, in order of files
+            handles.into_iter()
+                .flat_map(|handle| {
+                    let (scanned, messages) = handle.join().unwrap();
+                    messages.into_iter().for_each(report);
+                    scanned
+                })
+                .collect()
+        });
+        let mut entities = vec![];
+        for (file, entity) in actual_files.iter().zip(scanned) {
+            if let Some(entity) = entity {
+                entities.push(ScannedEntity {
+                    id: self.scaner.get_id(entities.len() + 1),
+                    file_key: file.key.to_string(),
+                    pak: file.pak.to_string(),
+                    entity
+                });
             }
         }
-        output_string.trim_end_matches(",").to_string();
+        entities
+    }
+
+    pub fn run(&self, files: &Vfs) -> (String, String) {
+        let entities = self.scan(files);
+        (self.lua_table(&entities), self.json_array(&entities))
+    }
+
+    /// Lua table named `table_name` with all entities.
+    pub fn lua_table(&self, entities: &[ScannedEntity<T>]) -> String {
+        let mut output_string = format!("{} = {{\n", &self.table_name);
+        for scanned in entities {
+            output_string += &scanned.entity.to_lua(scanned.id.clone());
+        }
         output_string.push('}');
-        json_string = json_string.trim_end_matches(",").to_owned();
-        (output_string, json_string)
+        output_string
     }
+
+    /// Json array with all entities.
+    pub fn json_array(&self, entities: &[ScannedEntity<T>]) -> String {
+        let entities: Vec<String> = entities.iter().map(|scanned| scanned.entity.to_json()).collect();
+        format!("[\n{}\n]", entities.join(",\n"))
+    }
+}
+
+/// Processor of entities kept inline in items of reftable.
+fn reftable_processor<E: ReftableEntity>(table: &str, output: &str, reftable: &str) -> ScanProcessor<String> {
+    ScanProcessor::new(table.to_string(), output.to_string(), Box::new(ReftableItemCollector { reftable: reftable.to_string() }), Box::new(ReftableItemScaner::<E>::default()))
+}
+
+pub(crate) fn creature_processor() -> ScanProcessor<u16> {
+    ScanProcessor::new("MCCS_CREATURES".to_string(), "creatures.lua".to_string(), Box::new(CreatureFileCollector {}), Box::new(CreatureScaner { id: 0, tables: RunCache::default() }))
+}
+
+pub(crate) fn art_processor() -> ScanProcessor<u16> {
+    ScanProcessor::new("MCCS_ARTIFACTS".to_string(), "artifacts.lua".to_string(), Box::new(ArtFileCollector {}), Box::new(ArtScaner { id: 0, sets: RunCache::default() }))
 }
 
-pub fn configure_path(path: Option<&String>, file_key: &String, files: &HashMap<String, FileStructure>) -> String {
+pub(crate) fn spell_processor() -> ScanProcessor<u16> {
+    ScanProcessor::new("MCCS_SPELLS".to_string(), "spells.lua".to_string(), Box::new(SpellFileCollector {}), Box::new(SpellScaner { id: 0, places: RunCache::default() }))
+}
+
+pub(crate) fn skill_processor() -> ScanProcessor<String> {
+    reftable_processor::<SkillShared>("MCCS_SKILLS", "skills.lua", SKILLS_REFTABLE)
+}
+
+pub(crate) fn hero_class_processor() -> ScanProcessor<String> {
+    reftable_processor::<HeroClassShared>("MCCS_HERO_CLASSES", "hero_classes.lua", HERO_CLASSES_REFTABLE)
+}
+
+pub(crate) fn town_processor() -> ScanProcessor<String> {
+    ScanProcessor::new("MCCS_TOWNS".to_string(), "towns.lua".to_string(), Box::new(TownFileCollector {}), Box::new(TownScaner {}))
+}
+
+pub(crate) fn ability_processor() -> ScanProcessor<String> {
+    reftable_processor::<AbilityShared>("MCCS_ABILITIES", "abilities.lua", ABILITIES_REFTABLE)
+}
+
+pub(crate) fn art_set_processor() -> ScanProcessor<String> {
+    reftable_processor::<ArtifactSetShared>("MCCS_ARTIFACT_SETS", "artifact_sets.lua", ARTIFACT_SETS_REFTABLE)
+}
+
+pub(crate) fn adv_object_processor() -> ScanProcessor<String> {
+    ScanProcessor::new("MCCS_ADV_OBJECTS".to_string(), "adv_objects.lua".to_string(), Box::new(AdvObjectFileCollector {}), Box::new(AdvObjectScaner {}))
+}
+
+pub(crate) fn creature_bank_processor() -> ScanProcessor<String> {
+    ScanProcessor::new("MCCS_CREATURE_BANKS".to_string(), "creature_banks.lua".to_string(), Box::new(CreatureBankFileCollector {}), Box::new(CreatureBankScaner { powers: RunCache::default() }))
+}
+
+pub(crate) fn hero_processor() -> ScanProcessor<String> {
+    ScanProcessor::new("MCCS_HEROES".to_string(), "heroes.lua".to_string(), Box::new(HeroFileCollector {}), Box::new(HeroScaner { classes: RunCache::default() }))
+}
+
+/// Reads `objects` table of reftable xdb, returning ids together with keys of files they point to.
+pub fn read_reftable(files: &HashMap<String, FileStructure>, reftable: &str) -> Vec<(String, Option<String>)> {
+    let mut ids = vec![];
+    let reftable_file = match files.get(&reftable.to_lowercase()) {
+        Some(file) => file,
+        None => {
+            crate::diagnostic!("Reftable {} is not in files", reftable);
+            return ids;
+        }
+    };
+    match deserialize_element::<FileObjects>(&reftable_file.content, "objects") {
+        Some(Ok(objects)) => {
+            for object in objects.objects {
+                let key = object.Obj
+                    .and_then(|obj| obj.href)
+                    .map(|href| href.split('#').next().unwrap_or_default().trim_start_matches('/').to_lowercase());
+                ids.push((object.ID, key));
+            }
+        },
+        Some(Err(e)) => crate::diagnostic!("Error deserializing {}, {}", reftable, e),
+        None => ()
+    }
+    ids
+}
+
+/// Reads every item of reftable `objects`, entities are expected to be inline.
+pub fn read_reftable_items<T: DeserializeOwned>(files: &HashMap<String, FileStructure>, reftable: &str) -> Vec<ReftableItem<T>> {
+    let reftable_file = match files.get(reftable) {
+        Some(file) => file,
+        None => {
+            crate::diagnostic!("Reftable {} is not in files", reftable);
+            return vec![];
+        }
+    };
+    match deserialize_element::<Items<ReftableItem<T>>>(&reftable_file.content, "objects") {
+        Some(Ok(items)) => items.items.unwrap_or_default(),
+        Some(Err(e)) => {
+            crate::diagnostic!("Error deserializing {}, {}", reftable, e);
+            vec![]
+        },
+        None => vec![]
+    }
+}
+
+/// Reads every entity of reftable in order they are listed.
+pub fn read_reftable_entities<E: ReftableEntity>(files: &HashMap<String, FileStructure>, reftable: &str) -> Vec<E> {
+    read_reftable_items::<E>(files, reftable).into_iter()
+        .filter_map(|item| item.obj?.complete(item.ID, reftable, files))
+        .collect()
+}
+
+/// Deserializes first element with given name in a single pass, starting right at its start tag.
+/// Attributes of the element itself are ignored unless `T` has `@`-prefixed fields for them.
+/// Returns `None` if there is no such element.
+pub fn deserialize_element<'de, T: Deserialize<'de>>(content: &'de str, tag: &str) -> Option<Result<T, DeError>> {
+    let mut reader = Reader::from_str(content);
+    loop {
+        let start = reader.buffer_position();
+        match reader.read_event() {
+            Err(e) => break Some(Err(e.into())),
+            Ok(Event::Eof) => break None,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.name().as_ref() == tag.as_bytes() => {
+                // deserializer stops after end of the element, so rest of content is never parsed
+                break Some(quick_xml::de::from_str(&content[start..]));
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Replaces reference with key of file it points to, dropping xpointer.
+pub fn resolve_file_ref(file_ref: &mut Option<FileRef>, file_key: &str, files: &HashMap<String, FileStructure>) {
+    if let Some(href) = file_ref.as_ref().and_then(|file_ref| file_ref.href.as_ref()) {
+        let path = href.split('#').next().unwrap_or_default().to_string();
+        *file_ref = Some(FileRef { href: Some(configure_path(Some(&path), file_key, files)) });
+    }
+}
+
+/// Key of file path points to, path is relative to file with `file_key` if it is not a key itself.
+pub fn configure_path(path: Option<&String>, file_key: &str, files: &HashMap<String, FileStructure>) -> String {
     match path {
         Some(actual_path) => {
-            let actual_path = actual_path.trim_start_matches("/").to_lowercase();
-            println!("actual path is {}", &actual_path);
-            if files.contains_key(&actual_path) == false {
-                println!("and it not in files");
-                let actual_name = file_key.rsplit_once("/").unwrap().0.to_string() + &format!("/{}", &actual_path);
-                //println!("name was {}, key {}, actual name {}", actual_path, file_key, &actual_name);
-                actual_name
+            let actual_path = actual_path.trim_start_matches('/').to_lowercase();
+            if files.contains_key(&actual_path) {
+                return actual_path;
             }
-            else {
-                actual_path
+            let actual_name = match file_key.rsplit_once('/') {
+                Some((dir, _)) => format!("{}/{}", dir, &actual_path),
+                None => actual_path.clone()
+            };
+            if !actual_path.is_empty() && !files.contains_key(&actual_name) {
+                crate::diagnostic!("Path {} referenced by {} is not in files", &actual_path, file_key);
             }
+            actual_name
         }
         None => {
             String::new()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::configured::ScannerSpec;
+
+    #[test]
+    fn parallel_scan_is_same_as_sequential() {
+        let mut documents = vec![];
+        let mut items = String::new();
+        for n in 0..20 {
+            items += &format!(r#"<Item><ID>SPELL_{n}</ID><Obj href="/Spells/{n}.xdb#xpointer(/Spell)"/></Item>"#);
+            // every third file is not a spell, so it is not scanned and ids of next spells shift
+            let root = if n % 3 == 0 { "Other" } else { "Spell" };
+            documents.push((format!("spells/{n}.xdb"), format!("<{root}><Level>{}</Level></{root}>", n % 5)));
+        }
+        documents.push(("spells.xdb".to_string(), format!("<Table><objects>{}</objects></Table>", items)));
+        let files = Vfs::from_files(documents.into_iter()
+            .map(|(key, content)| (key, FileStructure { pak: "data.pak".to_string(), modified: 0, content }))
+            .collect());
+        let processor = ScannerSpec::from_json(r#"{
+            "table_name": "TEST",
+            "output_file_name": "test.lua",
+            "source": { "reftable": { "path": "spells.xdb" } },
+            "root": "Spell",
+            "fields": [{ "path": "Level", "key": "level" }]
+        }"#).unwrap().processor();
+        let collected = processor.collect(&files);
+        let sequential = processor.scan_in_chunks(&collected, &files, 1);
+        assert_eq!(sequential.len(), 13);
+        for chunks in [2, 3, 7, 20, 64] {
+            let parallel = processor.scan_in_chunks(&collected, &files, chunks);
+            assert_eq!(processor.lua_table(&parallel), processor.lua_table(&sequential), "{} chunks", chunks);
+            assert_eq!(processor.json_array(&parallel), processor.json_array(&sequential), "{} chunks", chunks);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Constants of game mechanics, mods change them, so scripts should not hardcode them.
+//! Unlike other entities rules are single object, so they are exported as one flat table.
+//! Only `DefaultStats.xdb` is read, constants kept in other reftables are not exported yet.
+
+use serde::{Serialize, Deserialize};
+use homm5_types::creature::Resources;
+use super::{deserialize_element, town::lua_cost, Items};
+use crate::{export::{Export, ExportedTable, ScanCache}, output::lua_list, pak::Vfs};
+
+pub const RULES_FILE: &str = "gamemechanics/rpgstats/defaultstats.xdb";
+pub const RULES_ROOT: &str = "RPGStats";
+
+/// Every rule is optional, so one missing rule doesn't hide others. Missing rules are `nil` in lua
+/// and reported by `missing`, scripts must not get zeros instead of real values.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct GameRules {
+    /// Experience needed for every level, starting from the first one.
+    pub ExpTable: Option<Items<u64>>,
+    /// Movement points of hero on land without any bonuses.
+    pub HeroMovePoints: Option<u32>,
+    /// Effects of morale values, starting from the lowest one.
+    pub MoraleEffects: Option<Items<f32>>,
+    /// Effects of luck values, starting from the lowest one.
+    pub LuckEffects: Option<Items<f32>>,
+    /// Prices of resources in gold on market.
+    pub MarketPrices: Option<Resources>
+}
+
+fn lua_items<T: ToString>(items: &Option<Items<T>>) -> String {
+    match items {
+        Some(items) => lua_list(items.items.iter().flatten(), |item| item.to_string()),
+        None => "nil".to_string()
+    }
+}
+
+impl GameRules {
+    /// Names of rules that are not in rules file.
+    pub fn missing(&self) -> Vec<&'static str> {
+        [
+            ("ExpTable", self.ExpTable.is_none()),
+            ("HeroMovePoints", self.HeroMovePoints.is_none()),
+            ("MoraleEffects", self.MoraleEffects.is_none()),
+            ("LuckEffects", self.LuckEffects.is_none()),
+            ("MarketPrices", self.MarketPrices.is_none())
+        ].into_iter()
+            .filter_map(|(name, is_missing)| is_missing.then_some(name))
+            .collect()
+    }
+
+    pub fn to_lua(&self, table_name: &str) -> String {
+        let fields = [
+            ("exp_table", lua_items(&self.ExpTable)),
+            ("move_points", self.HeroMovePoints.map(|points| points.to_string()).unwrap_or_else(|| "nil".to_string())),
+            ("morale_effects", lua_items(&self.MoraleEffects)),
+            ("luck_effects", lua_items(&self.LuckEffects)),
+            ("market_prices", self.MarketPrices.as_ref().map(lua_cost).unwrap_or_else(|| "nil".to_string()))
+        ];
+        let fields: Vec<String> = fields.iter().map(|(name, value)| format!("    {} = {}", name, value)).collect();
+        format!("{} = {{\n{}\n}}", table_name, fields.join(",\n"))
+    }
+}
+
+/// Reads rules from `DefaultStats.xdb`, `None` if there is no such file or it can't be read.
+pub fn read_rules(files: &Vfs) -> Option<GameRules> {
+    match deserialize_element::<GameRules>(&files.get(RULES_FILE)?.content, RULES_ROOT)? {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            crate::diagnostic!("error while deserializing rules, {:?}", e.to_string());
+            None
+        }
+    }
+}
+
+/// Exports rules as a single table.
+pub struct RulesExport {}
+
+impl Export for RulesExport {
+    fn table_name(&self) -> &str {
+        "MCCS_RULES"
+    }
+
+    fn output_file_name(&self) -> &str {
+        "rules.lua"
+    }
+
+    fn export(&self, files: &Vfs, _scans: &ScanCache) -> ExportedTable {
+        let rules = read_rules(files);
+        let diagnostics = rules.iter()
+            .flat_map(|rules| rules.missing())
+            .map(|name| format!("Rule {} is not in {}", name, RULES_FILE))
+            .collect();
+        ExportedTable {
+            collected: files.contains_key(RULES_FILE) as usize,
+            scanned: rules.is_some() as usize,
+            lua: rules.as_ref().map(|rules| rules.to_lua(self.table_name())).unwrap_or_else(|| format!("{} = {{}}", self.table_name())),
+            json: serde_json::to_string_pretty(&rules).unwrap(),
+            diagnostics
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pak::FileStructure;
+
+    fn rules_file(content: &str) -> Vfs {
+        let file = FileStructure { pak: "data.pak".to_string(), modified: 0, content: content.to_string() };
+        Vfs::from_files([(RULES_FILE.to_string(), file)].into_iter().collect())
+    }
+
+    #[test]
+    fn missing_rules_are_nil_and_reported() {
+        let files = rules_file("<RPGStats><HeroMovePoints>1500</HeroMovePoints><ExpTable><Item>0</Item><Item>1000</Item></ExpTable></RPGStats>");
+        let exported = RulesExport {}.export(&files, &ScanCache::default());
+        assert_eq!(exported.scanned, 1);
+        assert!(exported.lua.contains("exp_table = {0, 1000, }"));
+        assert!(exported.lua.contains("move_points = 1500"));
+        assert!(exported.lua.contains("morale_effects = nil"));
+        assert_eq!(exported.diagnostics, [
+            "Rule MoraleEffects is not in gamemechanics/rpgstats/defaultstats.xdb",
+            "Rule LuckEffects is not in gamemechanics/rpgstats/defaultstats.xdb",
+            "Rule MarketPrices is not in gamemechanics/rpgstats/defaultstats.xdb"
+        ]);
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::common::FileRef;
+use super::{configure_path, deserialize_element, FileStructure, Items, ReftableEntity};
+use crate::{impl_output, xdb::{resolve_href, root_element}};
+
+pub const SKILLS_REFTABLE: &str = "gamemechanics/reftables/skills.xdb";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkillKind {
+    /// `SKILLTYPE_SKILL`
+    Skill,
+    /// `SKILLTYPE_STANDART_PERK`
+    Perk,
+    /// `SKILLTYPE_SPECIAL_PERK`
+    Racial,
+    #[default]
+    Unknown
+}
+
+impl SkillKind {
+    pub fn from_skill_type(skill_type: &str) -> Self {
+        match skill_type {
+            "SKILLTYPE_SKILL" => SkillKind::Skill,
+            "SKILLTYPE_STANDART_PERK" => SkillKind::Perk,
+            "SKILLTYPE_SPECIAL_PERK" => SkillKind::Racial,
+            _ => SkillKind::Unknown
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkillKind::Skill => "skill",
+            SkillKind::Perk => "perk",
+            SkillKind::Racial => "racial",
+            SkillKind::Unknown => "unknown"
+        }
+    }
+}
+
+/// Perks class must have to learn skill.
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SkillPrerequisite {
+    pub Class: String,
+    pub dependenciesIDs: Option<Items<String>>
+}
+
+/// Skill or perk, names, descriptions and icons are given for every mastery level.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SkillShared {
+    /// Id of reftable item, not a part of skill element.
+    #[serde(default)]
+    pub ID: String,
+    /// Set if skill is in separate file.
+    #[serde(rename = "@href", default, skip_serializing)]
+    pub href: Option<String>,
+    #[serde(default)]
+    pub NameFileRef: Items<FileRef>,
+    #[serde(default)]
+    pub DescriptionFileRef: Items<FileRef>,
+    #[serde(default)]
+    pub Texture: Items<FileRef>,
+    #[serde(default)]
+    pub SkillType: String,
+    #[serde(default)]
+    pub BasicSkillID: String,
+    #[serde(default)]
+    pub SkillPrerequisites: Items<SkillPrerequisite>,
+    #[serde(skip_deserializing)]
+    pub kind: SkillKind
+}
+
+impl SkillShared {
+    /// Perks required by any class, without repeats.
+    pub fn prerequisites(&self) -> Vec<&String> {
+        let mut prerequisites = vec![];
+        for prerequisite in self.SkillPrerequisites.items.iter().flatten() {
+            let dependencies = prerequisite.dependenciesIDs.as_ref().and_then(|ids| ids.items.as_ref());
+            for dependency in dependencies.into_iter().flatten() {
+                if !prerequisites.contains(&dependency) {
+                    prerequisites.push(dependency);
+                }
+            }
+        }
+        prerequisites
+    }
+}
+
+fn href_string(file_ref: &FileRef) -> String {
+    format!("\"{}\"", file_ref.href.as_deref().unwrap_or_default())
+}
+
+impl_output! {
+    SkillShared as skill, ID = String, key(_id) = value skill.ID, identity = Some(skill.ID.clone());
+    kind: string = skill.kind.as_str(),
+    base_skill: value = Some(skill.BasicSkillID.as_str()).filter(|id| !id.is_empty()).unwrap_or("nil"),
+    prerequisites: list = Some(skill.prerequisites()),
+    names: list = skill.NameFileRef.items => href_string,
+    descs: list = skill.DescriptionFileRef.items => href_string,
+    icons: list = skill.Texture.items => href_string
+}
+
+/// Reads skill kept in separate file, returning key of file too.
+fn read_skill_file(href: &str, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<(String, SkillShared)> {
+    let skill_key = resolve_href(href, file_key, files)?;
+    let content = &files[&skill_key].content;
+    match deserialize_element::<SkillShared>(content, &root_element(content)?)? {
+        Ok(skill) => Some((skill_key, skill)),
+        Err(e) => {
+            crate::diagnostic!("error while deserializing file key {}, {:?}", skill_key, e.to_string());
+            None
+        }
+    }
+}
+
+fn resolve_refs(refs: &mut Items<FileRef>, file_key: &str, files: &HashMap<String, FileStructure>) {
+    for file_ref in refs.items.iter_mut().flatten() {
+        if let Some(href) = file_ref.href.as_ref() {
+            let path = href.split('#').next().unwrap_or_default().to_string();
+            file_ref.href = Some(configure_path(Some(&path), file_key, files));
+        }
+    }
+}
+
+impl ReftableEntity for SkillShared {
+    const NAME: &'static str = "skill";
+
+    fn complete(self, id: String, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<Self> {
+        let (skill_key, mut skill) = match self.href {
+            Some(href) => read_skill_file(&href, file_key, files)?,
+            None => (file_key.to_string(), self)
+        };
+        if skill.SkillType.is_empty() {
+            crate::diagnostic!("Skill {} has no type, skipped", id);
+            return None;
+        }
+        skill.ID = id;
+        skill.kind = SkillKind::from_skill_type(&skill.SkillType);
+        resolve_refs(&mut skill.NameFileRef, &skill_key, files);
+        resolve_refs(&mut skill.DescriptionFileRef, &skill_key, files);
+        resolve_refs(&mut skill.Texture, &skill_key, files);
+        Some(skill)
+    }
+}
@@ -1,158 +1,335 @@
-use super::{configure_path, CollectFiles, FileObjects, FileStructure, Output, Scan};
-use quick_xml::{Reader, events::Event};
-use std::collections::HashMap;
-use homm5_types::{common::FileRef, spell::SpellShared};
-
-impl Output for SpellShared {
-    type ID = u16;
-    fn to_lua(&self, id: Option<Self::ID>) -> String {
-        let is_aimed = if self.IsAimed == true {"1"} else {"nil"};
-        let is_area = if self.IsAreaAttack == true {"1"} else {"nil"};
-        format!(
-            "\t[{}] = {{
-        name = \"{}\",
-        desc = \"{}\",
-        icon = \"{}\",
-        school = {},
-        level = {},
-        is_aimed = {},
-        is_area = {}
-    }},\n", 
-            id.unwrap(),
-            self.NameFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()), 
-            self.LongDescriptionFileRef.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.Texture.as_ref().unwrap().href.as_ref().unwrap_or(&String::new()),
-            self.MagicSchool,
-            self.Level,
-            is_aimed,
-            is_area
-        )
-    }
-
-    fn to_json(&self) -> String {
-        serde_json::to_string_pretty(self).unwrap()
-    }
-}
-
-pub struct SpellFileCollector { 
+use serde::{Serialize, Deserialize};
+use super::{
+    deserialize_element, read_reftable, resolve_file_ref, spell_processor, town::lua_cost,
+    CollectFiles, CollectedFile, FileObjects, FileStructure, Items, Output, RunCache, Scan, Vfs
+};
+use crate::{export::{Export, ExportedTable, ScanCache}, impl_output, output::{lua_entry, lua_list}};
+use std::{collections::HashMap, ops::Deref};
+use homm5_types::{common::FileRef, creature::Resources, spell::SpellShared};
+
+pub const SPELLS_REFTABLE: &str = "gamemechanics/reftables/undividedspells.xdb";
+
+/// Tribes of the East keep runes and warcries in spells reftable, they are told apart by magic school.
+/// Racial abilities are hero skills rather than spells, they are exported with skills.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpellKind {
+    #[default]
+    Spell,
+    /// `MAGIC_SCHOOL_RUNIC`
+    Rune,
+    /// `MAGIC_SCHOOL_WARCRIES`
+    Warcry
+}
+
+impl SpellKind {
+    pub fn from_school(school: &str) -> Self {
+        match school {
+            "MAGIC_SCHOOL_RUNIC" => SpellKind::Rune,
+            "MAGIC_SCHOOL_WARCRIES" => SpellKind::Warcry,
+            _ => SpellKind::Spell
+        }
+    }
+}
+
+/// Number growing with spell power: `Base + PerPower * power`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct SpellEffect {
+    pub Base: f32,
+    pub PerPower: f32
+}
+
+impl SpellEffect {
+    pub fn value(&self, spell_power: u16) -> f32 {
+        self.Base + self.PerPower * spell_power as f32
+    }
+}
+
+/// Index of mastery in per mastery lists of spell, `MASTERY_NONE` is the first one.
+pub fn mastery_index(mastery: &str) -> Option<usize> {
+    match mastery {
+        "MASTERY_NONE" => Some(0),
+        "MASTERY_BASIC" => Some(1),
+        "MASTERY_ADVANCED" => Some(2),
+        "MASTERY_EXPERT" => Some(3),
+        _ => None
+    }
+}
+
+/// Numeric parameters of spell, not read by `SpellShared`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct SpellNumbers {
+    /// Rage for warcries.
+    pub ManaCost: u16,
+    /// Resources spent to cast rune.
+    pub TrainedCost: Option<Resources>,
+    /// Damage for every mastery.
+    pub damage: Items<SpellEffect>,
+    /// Duration in turns for every mastery.
+    pub duration: Items<SpellEffect>
+}
+
+/// `<Spell>` element read in one pass, it is split into `SpellShared` and `SpellNumbers`.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SpellElement {
+    NameFileRef: Option<FileRef>,
+    LongDescriptionFileRef: Option<FileRef>,
+    Texture: Option<FileRef>,
+    Level: u8,
+    MagicSchool: String,
+    IsAimed: bool,
+    IsAreaAttack: bool,
+    #[serde(default)]
+    ManaCost: u16,
+    #[serde(default)]
+    TrainedCost: Option<Resources>,
+    #[serde(default)]
+    damage: Items<SpellEffect>,
+    #[serde(default)]
+    duration: Items<SpellEffect>
+}
+
+impl SpellElement {
+    fn split(self) -> (SpellShared, SpellNumbers) {
+        let shared = SpellShared {
+            NameFileRef: self.NameFileRef,
+            LongDescriptionFileRef: self.LongDescriptionFileRef,
+            Texture: self.Texture,
+            Level: self.Level,
+            MagicSchool: self.MagicSchool,
+            IsAimed: self.IsAimed,
+            IsAreaAttack: self.IsAreaAttack
+        };
+        let numbers = SpellNumbers {
+            ManaCost: self.ManaCost,
+            TrainedCost: self.TrainedCost,
+            damage: self.damage,
+            duration: self.duration
+        };
+        (shared, numbers)
+    }
+}
+
+/// Spell together with its effect numbers and place in spells reftable.
+#[derive(Debug, Serialize)]
+pub struct Spell {
+    #[serde(flatten)]
+    pub shared: SpellShared,
+    #[serde(flatten)]
+    pub numbers: SpellNumbers,
+    pub kind: SpellKind,
+    /// Id of spell in spells reftable, never empty for scanned spells.
+    pub id: String,
+    /// Position of spell among items of reftable referencing spells, starting from 1.
+    /// It doesn't depend on kinds of other spells, so scripts can rely on it.
+    pub index: u16
+}
+
+impl Deref for Spell {
+    type Target = SpellShared;
+
+    fn deref(&self) -> &SpellShared {
+        &self.shared
+    }
+}
+
+impl Spell {
+    /// Damage spell deals with given spell power and mastery, `None` if spell has no damage for this mastery.
+    pub fn damage(&self, spell_power: u16, mastery: &str) -> Option<f32> {
+        let effects = self.numbers.damage.items.as_ref()?;
+        effects.get(mastery_index(mastery)?).map(|effect| effect.value(spell_power))
+    }
+}
+
+fn lua_effects(effects: &Items<SpellEffect>) -> String {
+    lua_list(effects.items.iter().flatten(), |effect| format!("{{base = {}, per_power = {}}}", effect.Base, effect.PerPower))
+}
+
+impl_output! {
+    Spell as spell, ID = u16, key(_id) = value spell.index, identity = Some(spell.id.clone());
+    name: href = spell.NameFileRef,
+    desc: href = spell.LongDescriptionFileRef,
+    icon: href = spell.Texture,
+    school: value = spell.MagicSchool,
+    level: value = spell.Level,
+    is_aimed: bool = spell.IsAimed,
+    is_area: bool = spell.IsAreaAttack,
+    mana_cost: value = spell.numbers.ManaCost,
+    damage: value = lua_effects(&spell.numbers.damage),
+    duration: value = lua_effects(&spell.numbers.duration)
 }
 
+impl Spell {
+    /// Entry of table of spell's kind, spells are keyed by index, runes and warcries by reftable id.
+    fn to_kind_lua(&self) -> String {
+        let common = [
+            ("name", crate::lua_value!(href self.NameFileRef)),
+            ("desc", crate::lua_value!(href self.LongDescriptionFileRef)),
+            ("icon", crate::lua_value!(href self.Texture)),
+            ("level", self.Level.to_string())
+        ];
+        match self.kind {
+            SpellKind::Spell => self.to_lua(None),
+            // dwarven runes are paid with resources instead of mana
+            SpellKind::Rune => lua_entry(&self.id, &[
+                &common[..],
+                &[
+                    ("cost", self.numbers.TrainedCost.as_ref().map(lua_cost).unwrap_or_else(|| "{}".to_string())),
+                    ("duration", lua_effects(&self.numbers.duration))
+                ]
+            ].concat()),
+            // orcish warcries are paid with rage of hero
+            SpellKind::Warcry => lua_entry(&self.id, &[
+                &common[..],
+                &[
+                    ("rage_cost", self.numbers.ManaCost.to_string()),
+                    ("damage", lua_effects(&self.numbers.damage)),
+                    ("duration", lua_effects(&self.numbers.duration))
+                ]
+            ].concat())
+        }
+    }
+}
+
+/// Collects every spell file of spells reftable, runes and warcries too.
+pub struct SpellFileCollector {}
+
 impl CollectFiles for SpellFileCollector {
-    fn collect(&self, files: &HashMap<String, FileStructure>, collected_files: &mut Vec<(String, FileStructure)>) {
-        let spells_xdb = files.iter()
-        .find(|f| f.0 == "GameMechanics/RefTables/UndividedSpells.xdb".to_lowercase().as_str())
-        .unwrap();
-    let mut buf = Vec::new();
-    let mut reader = Reader::from_str(spells_xdb.1.content.as_str());
-    reader.trim_text(true);
-    reader.expand_empty_elements(true);
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-            Ok(Event::Eof) => break,
-            Ok(Event::Start(e)) => {
-                match e.name().as_ref() {
-                    b"objects" => {
-                        let end = e.to_end().into_owned();
-                        let text = reader.read_text(end.name()).unwrap().to_string();
-                        let text = format!("<objects>{}</objects>", text);
-                        let spells_de: Result<FileObjects, quick_xml::DeError> = quick_xml::de::from_str(&text);
-                        match spells_de {
-                            Ok(spells) => {
-                                for spell in spells.objects {
-                                    match spell.Obj {
-                                        Some(obj) => {
-                                            match obj.href.as_ref() {
-                                                Some(href) => {
-                                                    let spell_key = href
-                                                        .replace("#xpointer(/Spell)", "")
-                                                        .trim_start_matches("/")
-                                                        .to_lowercase();
-                                                    let spell_entity = files.get(&spell_key);
-                                                    match spell_entity {
-                                                        Some(entity) => {
-                                                            collected_files.push((spell_key.clone(), entity.clone()));
-                                                        },
-                                                        None => println!("Key {} is not in files", &spell_key)
-                                                    }
-                                                },
-                                                None => {}
-                                            }
-                                        }
-                                        None => {}
-                                    }
-                                }
-                            },
-                            Err(e) => println!("Error deserializing spells.xdb, {}", e.to_string())
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        let spells_xdb = match files.get(SPELLS_REFTABLE) {
+            Some(reftable) => reftable,
+            None => {
+                crate::diagnostic!("Reftable {} is not in files", SPELLS_REFTABLE);
+                return;
+            }
+        };
+        match deserialize_element::<FileObjects>(&spells_xdb.content, "objects") {
+            Some(Ok(spells)) => {
+                for spell in spells.objects {
+                    if let Some(href) = spell.Obj.and_then(|obj| obj.href) {
+                        let spell_key = href
+                            .replace("#xpointer(/Spell)", "")
+                            .trim_start_matches('/')
+                            .to_lowercase();
+                        match files.get_key_value(&spell_key) {
+                            Some((key, entity)) => collected_files.push(CollectedFile::new(key, entity)),
+                            None => crate::diagnostic!("Key {} is not in files", &spell_key)
                         }
                     }
-                    _=> {}
                 }
-            }
-            _ => ()
+            },
+            Some(Err(e)) => crate::diagnostic!("Error deserializing spells.xdb, {}", e),
+            None => ()
         }
-        buf.clear();
     }
+}
+
+/// Ids and indices of spells by keys of their files, in order of spells reftable.
+fn read_spell_places(files: &HashMap<String, FileStructure>) -> HashMap<String, (String, u16)> {
+    let mut places = HashMap::new();
+    let spell_keys = read_reftable(files, SPELLS_REFTABLE).into_iter()
+        .filter_map(|(id, key)| Some((id, key?)));
+    for (index, (id, key)) in spell_keys.enumerate() {
+        places.entry(key).or_insert((id, index as u16 + 1));
+    }
+    places
+}
+
+/// Reads spell file resolving paths of its texts and icon, id and index are left for scaner.
+fn read_spell(file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Spell> {
+    match deserialize_element::<SpellElement>(entity, "Spell")? {
+        Ok(element) => {
+            let (mut spell, numbers) = element.split();
+            // missing references stay missing, validation reports them
+            resolve_file_ref(&mut spell.NameFileRef, file_key, files);
+            resolve_file_ref(&mut spell.LongDescriptionFileRef, file_key, files);
+            resolve_file_ref(&mut spell.Texture, file_key, files);
+            let kind = SpellKind::from_school(&spell.MagicSchool);
+            Some(Spell { shared: spell, numbers, kind, id: String::new(), index: 0 })
+        }
+        Err(e) => {
+            crate::diagnostic!("error while deserializing file key {}, {:?}", file_key, e.to_string());
+            None
+        }
     }
 }
 
 pub struct SpellScaner {
-    pub id: u16
+    pub id: u16,
+    /// Ids and indices of spells by keys of their files.
+    pub places: RunCache<HashMap<String, (String, u16)>>
 }
 
 impl Scan<u16> for SpellScaner {
-    fn scan(&mut self, file_key: &String, entity: &String, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
-        let mut buf = Vec::new();
-        let mut reader = Reader::from_str(entity);
-        reader.trim_text(true);
-        reader.expand_empty_elements(true);
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                Ok(Event::Eof) => break None,
-                Ok(Event::Start(e)) => {
-                    match e.name().as_ref() {
-                        b"Spell" => {
-                            let end = e.to_end().into_owned();
-                            let possible_text = reader.read_text(end.name());
-                            match possible_text {
-                                Ok(text) => {
-                                    let text = text.to_string();
-                                    let de_res: Result<SpellShared, quick_xml::DeError> = quick_xml::de::from_str(
-                                        &format!("<Spell>{}</Spell>", text)
-                                    );
-                                    match de_res {
-                                        Ok(mut spell) => {
-                                            let name = configure_path(spell.NameFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let desc = configure_path(spell.LongDescriptionFileRef.as_ref().unwrap().href.as_ref(), file_key, files);
-                                            let icon_key = spell.Texture.as_ref().unwrap().href.as_ref().unwrap_or(&String::new())
-                                                .replace("#xpointer(/Texture)", "")
-                                                .to_lowercase();
-                                            let icon = configure_path(Some(&icon_key), file_key, files);
-                                            spell.NameFileRef = Some(FileRef { href: Some(name) });
-                                            spell.LongDescriptionFileRef = Some(FileRef { href: Some(desc) });
-                                            spell.Texture = Some(FileRef { href: Some(icon) });
-                                            self.id+=1;
-                                            break Some(Box::new(spell));
-                                        }
-                                        Err(e) => {
-                                            println!("error while deserializing file key {}, {:?}", file_key, e.to_string());
-                                        }
-                                    }
-                                },
-                                Err(_e) => println!("error reading file content: {}", file_key)
-                            }
-                        }
-                        _=> {}
-                    }
-                }
-                _ => ()
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = u16>>> {
+        let mut spell = read_spell(file_key, entity, files)?;
+        // spells are keyed by their place in reftable, spell without one can't be written
+        match self.places.get_or_init(|| read_spell_places(files)).get(file_key) {
+            Some((id, index)) => {
+                spell.id = id.clone();
+                spell.index = *index;
+            },
+            None => {
+                crate::diagnostic!("Spell {} is not in spells reftable, skipped", file_key);
+                return None;
             }
-            buf.clear();
         }
+        Some(Box::new(spell))
     }
 
-    fn get_id(&self) -> Option<u16> {
-        Some(self.id)
+    fn get_id(&self, position: usize) -> Option<u16> {
+        Some(self.id + position as u16)
     }
-}
\ No newline at end of file
+
+    fn prepare(&self) {
+        self.places.clear();
+    }
+}
+
+/// Exports spells of one kind, tables of all kinds are made of the same scan of spells reftable.
+pub struct SpellTableExport {
+    pub kind: SpellKind
+}
+
+impl Export for SpellTableExport {
+    fn table_name(&self) -> &str {
+        match self.kind {
+            SpellKind::Spell => "MCCS_SPELLS",
+            SpellKind::Rune => "MCCS_RUNES",
+            SpellKind::Warcry => "MCCS_WARCRIES"
+        }
+    }
+
+    fn output_file_name(&self) -> &str {
+        match self.kind {
+            SpellKind::Spell => "spells.lua",
+            SpellKind::Rune => "runes.lua",
+            SpellKind::Warcry => "warcries.lua"
+        }
+    }
+
+    fn export(&self, files: &Vfs, scans: &ScanCache) -> ExportedTable {
+        let scanned = scans.scan(&spell_processor(), files);
+        let spells: Vec<&Spell> = scanned.entities.iter().filter_map(|spell| spell.get::<Spell>()).collect();
+        let (spells, others): (Vec<&Spell>, Vec<&Spell>) = spells.into_iter().partition(|spell| spell.kind == self.kind);
+        let mut lua = format!("{} = {{\n", self.table_name());
+        for spell in &spells {
+            lua += &spell.to_kind_lua();
+        }
+        lua.push('}');
+        let json: Vec<String> = spells.iter().map(|spell| spell.to_json()).collect();
+        ExportedTable {
+            // kind of spells that were not scanned is unknown, so they are counted in tables of every kind
+            collected: scanned.collected - others.len(),
+            scanned: spells.len(),
+            lua,
+            json: format!("[\n{}\n]", json.join(",\n")),
+            diagnostics: vec![]
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Town type definitions: buildings of faction with their upgrade levels.
+//! Every level may require other buildings, these requirements together with requirement of previous level
+//! make dependency graph of town, that is exported as list of edges and as one of possible build orders.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use homm5_types::{common::FileRef, creature::Resources};
+use super::{deserialize_element, resolve_file_ref, CollectFiles, CollectedFile, FileStructure, Items, Output, Scan, Vfs};
+use crate::{impl_output, output::lua_list};
+
+pub const TOWN_TYPE_ROOT: &str = "TownType";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct BuildingRequirement {
+    pub Type: String,
+    /// `BLD_UPG_1`, `BLD_UPG_2`, ...
+    #[serde(default)]
+    pub Level: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct BuildingLevel {
+    pub NameFileRef: Option<FileRef>,
+    pub DescriptionFileRef: Option<FileRef>,
+    pub Icon: Option<FileRef>,
+    pub Cost: Option<Resources>,
+    #[serde(default)]
+    pub Requirements: Items<BuildingRequirement>,
+    /// Creature of dwelling.
+    pub Creature: Option<String>,
+    /// Additional weekly growth of dwelling creatures.
+    pub GrowthBonus: Option<u16>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct TownBuildingShared {
+    /// `TB_FORT`, `TB_DWELLING_1`, ...
+    pub Type: String,
+    #[serde(default)]
+    pub Levels: Items<BuildingLevel>
+}
+
+/// Spells offered by one level of mage guild.
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct GuildLevel {
+    #[serde(default)]
+    pub SpellsCount: u8,
+    /// Magic schools spells are taken from.
+    #[serde(default)]
+    pub Schools: Items<String>
+}
+
+/// Edge of dependency graph: `level` of `building` can be built only after `required_level` of `requires`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildingDependency {
+    pub building: String,
+    pub level: u8,
+    pub requires: String,
+    pub required_level: u8
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct TownTypeShared {
+    #[serde(alias = "TownType")]
+    pub Town: String,
+    #[serde(default)]
+    pub Buildings: Items<TownBuildingShared>,
+    /// Levels of mage guild, starting from the first one.
+    #[serde(default)]
+    pub MagicGuild: Items<GuildLevel>,
+    #[serde(skip_deserializing)]
+    pub dependencies: Vec<BuildingDependency>
+}
+
+/// Number of `BLD_UPG_N` level, requirement without level means first one.
+pub fn level_number(level: &str) -> u8 {
+    level.trim_start_matches("BLD_UPG_").parse().unwrap_or(1).max(1)
+}
+
+impl TownTypeShared {
+    pub fn buildings(&self) -> impl Iterator<Item = &TownBuildingShared> {
+        self.Buildings.items.iter().flatten()
+    }
+
+    /// Edges of dependency graph, including requirement of previous level of the same building.
+    pub fn collect_dependencies(&self) -> Vec<BuildingDependency> {
+        let mut dependencies = vec![];
+        for building in self.buildings() {
+            for (index, level) in building.Levels.items.iter().flatten().enumerate() {
+                let number = index as u8 + 1;
+                if number > 1 {
+                    dependencies.push(BuildingDependency {
+                        building: building.Type.clone(),
+                        level: number,
+                        requires: building.Type.clone(),
+                        required_level: number - 1
+                    });
+                }
+                for requirement in level.Requirements.items.iter().flatten() {
+                    dependencies.push(BuildingDependency {
+                        building: building.Type.clone(),
+                        level: number,
+                        requires: requirement.Type.clone(),
+                        required_level: level_number(&requirement.Level)
+                    });
+                }
+            }
+        }
+        dependencies
+    }
+
+    /// Every level of every building, ordered so that requirements are built first.
+    /// Levels are taken in order of definition whenever possible, levels in dependency cycle are left out.
+    pub fn build_order(&self) -> Vec<(String, u8)> {
+        let mut pending: Vec<(String, u8)> = self.buildings()
+            .flat_map(|building| (1..=building.Levels.items.iter().flatten().count() as u8).map(|level| (building.Type.clone(), level)))
+            .collect();
+        let mut order: Vec<(String, u8)> = vec![];
+        loop {
+            let ready = pending.iter().position(|(building, level)| {
+                self.dependencies.iter()
+                    .filter(|dependency| &dependency.building == building && dependency.level == *level)
+                    .all(|dependency| order.iter().any(|(built, built_level)| built == &dependency.requires && *built_level >= dependency.required_level))
+            });
+            match ready {
+                Some(position) => order.push(pending.remove(position)),
+                None => break
+            }
+        }
+        order
+    }
+
+    fn lua_buildings(&self) -> String {
+        lua_list(self.buildings(), |building| {
+            let levels = lua_list(building.Levels.items.iter().flatten(), |level| {
+                let requires = lua_list(level.Requirements.items.iter().flatten(), |requirement| {
+                    format!("{{{}, {}}}", requirement.Type, level_number(&requirement.Level))
+                });
+                format!(
+                    "{{name = {}, desc = {}, icon = {}, cost = {}, requires = {}, creature = {}, growth = {}}}",
+                    href(&level.NameFileRef), href(&level.DescriptionFileRef), href(&level.Icon),
+                    level.Cost.as_ref().map(lua_cost).unwrap_or_else(|| "{}".to_string()),
+                    requires,
+                    level.Creature.as_deref().unwrap_or("nil"),
+                    level.GrowthBonus.unwrap_or_default()
+                )
+            });
+            format!("[{}] = {}", building.Type, levels)
+        })
+    }
+}
+
+fn href(file_ref: &Option<FileRef>) -> String {
+    format!("\"{}\"", file_ref.as_ref().and_then(|file_ref| file_ref.href.as_deref()).unwrap_or_default())
+}
+
+pub(crate) fn lua_cost(cost: &Resources) -> String {
+    format!(
+        "{{wood = {}, ore = {}, mercury = {}, crystal = {}, sulfur = {}, gem = {}, gold = {}}}",
+        cost.Wood, cost.Ore, cost.Mercury, cost.Crystal, cost.Sulfur, cost.Gem, cost.Gold
+    )
+}
+
+impl_output! {
+    TownTypeShared as town, ID = String, key(_id) = value town.Town, identity = Some(town.Town.clone());
+    buildings: value = town.lua_buildings(),
+    dependencies: value = lua_list(&town.dependencies, |dependency| format!(
+        "{{building = {}, level = {}, requires = {}, required_level = {}}}",
+        dependency.building, dependency.level, dependency.requires, dependency.required_level
+    )),
+    build_order: list = Some(town.build_order()) => |(building, level)| format!("{{{}, {}}}", building, level)
+}
+
+pub struct TownFileCollector {}
+
+impl CollectFiles for TownFileCollector {
+    fn collect<'a>(&self, files: &'a Vfs, collected_files: &mut Vec<CollectedFile<'a>>) {
+        collected_files.extend(files.files_with_root(TOWN_TYPE_ROOT).map(|f| CollectedFile::new(f.0, f.1)));
+    }
+}
+
+pub struct TownScaner {}
+
+impl Scan<String> for TownScaner {
+    fn scan(&self, file_key: &str, entity: &str, files: &HashMap<String, FileStructure>) -> Option<Box<dyn Output<ID = String>>> {
+        match deserialize_element::<TownTypeShared>(entity, TOWN_TYPE_ROOT)? {
+            Ok(mut town) => {
+                for building in town.Buildings.items.iter_mut().flatten() {
+                    for level in building.Levels.items.iter_mut().flatten() {
+                        resolve_file_ref(&mut level.NameFileRef, file_key, files);
+                        resolve_file_ref(&mut level.DescriptionFileRef, file_key, files);
+                        resolve_file_ref(&mut level.Icon, file_key, files);
+                    }
+                }
+                town.dependencies = town.collect_dependencies();
+                Some(Box::new(town))
+            }
+            Err(e) => {
+                crate::diagnostic!("error while deserializing file key {}, {:?}", file_key, e.to_string());
+                None
+            }
+        }
+    }
+
+    fn get_id(&self, _position: usize) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,262 @@
+//! Registry of named processors and pipeline writing their output to a directory.
+//! `Registry::new()` contains built-in processors, others are added with `Registry::add` or `Registry::add_spec`.
+
+use std::{any::Any, cell::RefCell, collections::HashMap, fs, path::{Path, PathBuf}, rc::Rc};
+use serde::Serialize;
+use crate::{
+    entity::{ability_processor, adv_object_processor, art_processor, art_set_processor, configured::ScannerSpec, creature::CreatureLineupExport, creature_bank_processor, creature_processor, guild::MagicGuildExport, hero_class_processor, hero_processor, rules::RulesExport, skill_processor, spell::{SpellKind, SpellTableExport}, town_processor, ScanProcessor, ScannedEntity},
+    pak::Vfs
+};
+
+thread_local! {
+    /// Messages reported on this thread while they are captured.
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Reports problem met while collecting or scanning files, like file that can't be deserialized.
+/// Messages reported during export go to diagnostics of table, others are printed.
+pub fn report(message: String) {
+    let uncaptured = CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(messages) => {
+            messages.push(message);
+            None
+        },
+        None => Some(message)
+    });
+    if let Some(message) = uncaptured {
+        println!("{}", message);
+    }
+}
+
+/// Runs `f` collecting messages reported on this thread instead of printing them.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    let outer = CAPTURED.with(|captured| captured.replace(Some(vec![])));
+    let result = f();
+    let messages = CAPTURED.with(|captured| captured.replace(outer)).unwrap_or_default();
+    (result, messages)
+}
+
+/// Formats message and reports it with `report`.
+#[macro_export]
+macro_rules! diagnostic {
+    ($($arg:tt)*) => {
+        $crate::export::report(format!($($arg)*))
+    };
+}
+
+/// Processor with its id type erased, so processors of different entities can be stored together.
+pub trait Export {
+    fn table_name(&self) -> &str;
+    fn output_file_name(&self) -> &str;
+    /// Exports table, entities are scanned through `scans`, so tables made of the same entities share them.
+    fn export(&self, files: &Vfs, scans: &ScanCache) -> ExportedTable;
+}
+
+/// Entities scanned by processor together with number of files collected for them.
+pub struct Scanned<T> {
+    pub collected: usize,
+    pub entities: Vec<ScannedEntity<T>>
+}
+
+/// Scans made during one export, by table names of processors.
+#[derive(Default)]
+pub struct ScanCache {
+    scans: RefCell<HashMap<String, Rc<dyn Any>>>
+}
+
+impl ScanCache {
+    /// Scans files with processor, unless processor with the same table name has scanned them already.
+    pub fn scan<T: Clone + 'static>(&self, processor: &ScanProcessor<T>, files: &Vfs) -> Rc<Scanned<T>> {
+        let cached = self.scans.borrow().get(&processor.table_name).cloned();
+        if let Some(scanned) = cached.and_then(|scanned| scanned.downcast::<Scanned<T>>().ok()) {
+            return scanned;
+        }
+        let collected = processor.collect(files);
+        let scanned = Rc::new(Scanned { collected: collected.len(), entities: processor.scan_collected(&collected, files) });
+        self.scans.borrow_mut().insert(processor.table_name.clone(), scanned.clone());
+        scanned
+    }
+}
+
+/// Text of one table together with numbers of files it was made of.
+pub struct ExportedTable {
+    pub collected: usize,
+    pub scanned: usize,
+    pub lua: String,
+    pub json: String,
+    /// Problems met while making table, messages reported during export are added to them.
+    pub diagnostics: Vec<String>
+}
+
+impl<T: Clone + 'static> Export for ScanProcessor<T> {
+    fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    fn output_file_name(&self) -> &str {
+        &self.output_file_name
+    }
+
+    fn export(&self, files: &Vfs, scans: &ScanCache) -> ExportedTable {
+        let scanned = scans.scan(self, files);
+        ExportedTable {
+            collected: scanned.collected,
+            scanned: scanned.entities.len(),
+            lua: self.lua_table(&scanned.entities),
+            json: self.json_array(&scanned.entities),
+            diagnostics: vec![]
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableManifest {
+    /// Name processor is registered with.
+    pub name: String,
+    pub table_name: String,
+    pub lua_file: PathBuf,
+    pub json_file: PathBuf,
+    /// Files chosen by collector.
+    pub collected: usize,
+    /// Entities successfully scanned from them.
+    pub scanned: usize,
+    pub diagnostics: Vec<String>
+}
+
+/// Result of export: what was written where and what went wrong.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Manifest {
+    pub output_dir: PathBuf,
+    pub tables: Vec<TableManifest>,
+    /// Problems not related to particular table, like unknown processor names.
+    pub diagnostics: Vec<String>
+}
+
+impl Manifest {
+    pub fn has_diagnostics(&self) -> bool {
+        !self.diagnostics.is_empty() || self.tables.iter().any(|table| !table.diagnostics.is_empty())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+pub struct Registry {
+    processors: Vec<(String, Box<dyn Export>)>
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            processors: vec![
+                ("creatures".to_string(), Box::new(creature_processor())),
+                ("creature_lineup".to_string(), Box::new(CreatureLineupExport {})),
+                ("abilities".to_string(), Box::new(ability_processor())),
+                ("artifacts".to_string(), Box::new(art_processor())),
+                ("artifact_sets".to_string(), Box::new(art_set_processor())),
+                ("spells".to_string(), Box::new(SpellTableExport { kind: SpellKind::Spell })),
+                ("runes".to_string(), Box::new(SpellTableExport { kind: SpellKind::Rune })),
+                ("warcries".to_string(), Box::new(SpellTableExport { kind: SpellKind::Warcry })),
+                ("heroes".to_string(), Box::new(hero_processor())),
+                ("hero_classes".to_string(), Box::new(hero_class_processor())),
+                ("skills".to_string(), Box::new(skill_processor())),
+                ("towns".to_string(), Box::new(town_processor())),
+                ("magic_guilds".to_string(), Box::new(MagicGuildExport {})),
+                ("adv_objects".to_string(), Box::new(adv_object_processor())),
+                ("creature_banks".to_string(), Box::new(creature_bank_processor())),
+                ("rules".to_string(), Box::new(RulesExport {}))
+            ]
+        }
+    }
+}
+
+impl Registry {
+    /// Registry with all built-in processors.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with no processors at all.
+    pub fn empty() -> Self {
+        Registry { processors: vec![] }
+    }
+
+    /// Adds processor, replacing one already registered with the same name.
+    pub fn add(&mut self, name: &str, processor: Box<dyn Export>) -> &mut Self {
+        match self.processors.iter_mut().find(|(registered, _)| registered == name) {
+            Some(registered) => registered.1 = processor,
+            None => self.processors.push((name.to_string(), processor))
+        }
+        self
+    }
+
+    /// Adds processor made from config-driven scanner spec.
+    pub fn add_spec(&mut self, name: &str, spec: ScannerSpec) -> &mut Self {
+        self.add(name, Box::new(spec.processor()))
+    }
+
+    /// Names of registered processors in order they run.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.processors.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Export> {
+        self.processors.iter()
+            .find(|(registered, _)| registered == name)
+            .map(|(_, processor)| processor.as_ref())
+    }
+
+    /// Runs every registered processor, see `export`.
+    pub fn export_all(&self, files: &Vfs, output_dir: &Path) -> Manifest {
+        let names: Vec<&str> = self.names().collect();
+        self.export(&names, files, output_dir)
+    }
+
+    /// Runs processors with given names and writes lua and json file of each into `output_dir`.
+    /// Json file is named as lua one with `.json` extension.
+    pub fn export(&self, names: &[&str], files: &Vfs, output_dir: &Path) -> Manifest {
+        let mut manifest = Manifest { output_dir: output_dir.to_path_buf(), ..Default::default() };
+        if let Err(e) = fs::create_dir_all(output_dir) {
+            manifest.diagnostics.push(format!("Can't create output directory {}: {}", output_dir.display(), e));
+            return manifest;
+        }
+        let scans = ScanCache::default();
+        for name in names {
+            match self.get(name) {
+                Some(processor) => manifest.tables.push(export_table(name, processor, files, &scans, output_dir)),
+                None => manifest.diagnostics.push(format!("Unknown processor {}", name))
+            }
+        }
+        manifest
+    }
+}
+
+fn export_table(name: &str, processor: &dyn Export, files: &Vfs, scans: &ScanCache, output_dir: &Path) -> TableManifest {
+    let (exported, reported) = capture(|| processor.export(files, scans));
+    let lua_file = output_dir.join(processor.output_file_name());
+    let json_file = lua_file.with_extension("json");
+    let mut diagnostics = vec![];
+    if exported.collected == 0 {
+        diagnostics.push("No files collected".to_string());
+    }
+    else if exported.scanned < exported.collected {
+        diagnostics.push(format!("{} of {} collected files were not scanned", exported.collected - exported.scanned, exported.collected));
+    }
+    diagnostics.extend(exported.diagnostics);
+    diagnostics.extend(reported);
+    for (file, content) in [(&lua_file, &exported.lua), (&json_file, &exported.json)] {
+        if let Err(e) = fs::write(file, content) {
+            diagnostics.push(format!("Can't write {}: {}", file.display(), e));
+        }
+    }
+    TableManifest {
+        name: name.to_string(),
+        table_name: processor.table_name().to_string(),
+        lua_file,
+        json_file,
+        collected: exported.collected,
+        scanned: exported.scanned,
+        diagnostics
+    }
+}
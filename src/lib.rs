@@ -1,3 +1,8 @@
 pub mod pak;
 pub mod entity;
-pub mod output;
\ No newline at end of file
+pub mod output;
+pub mod query;
+pub mod diff;
+pub mod validation;
+pub mod xdb;
+pub mod export;
\ No newline at end of file
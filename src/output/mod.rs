@@ -1,3 +1,106 @@
+use serde::Serialize;
+
 pub trait OutputJson {
     fn try_output(&self) -> String;
-}
\ No newline at end of file
+}
+
+/// Generates `Output` implementation for entity from list of its lua fields.
+/// ```ignore
+/// impl_output! {
+///     Artifact as art, ID = u16, key(id) = value id.unwrap() - 1, identity = Some(art.id.clone());
+///     is_sellable: bool = art.CanBeGeneratedToSell,
+///     name: href = art.NameFileRef,
+///     cost: value = art.CostOfGold,
+///     abilities: list = art.Abilities,
+///     spells: list = art.Spells => |spell| format!("[{}] = {}", spell.Spell, spell.Mastery)
+/// }
+/// ```
+/// Field kinds are:
+/// - `value` - written as is,
+/// - `string` - written in quotes,
+/// - `bool` - `1` or `nil`,
+/// - `href` - path of `Option<FileRef>`(or `Option<&FileRef>`) in quotes, empty if there is no reference,
+/// - `list` - `Option<Vec<_>>` as lua list, items are written as is or mapped with closure after `=>`.
+///
+/// Same kinds are used for entity key.
+/// Optional `identity` is `Option<String>` that doesn't depend on order of scanned files, see `Output::identity`.
+#[macro_export]
+macro_rules! impl_output {
+    (
+        $entity:ty as $this:ident, ID = $id_type:ty, key($id:ident) = $key_kind:ident $key:expr $(, identity = $identity:expr)?;
+        $($field:tt : $kind:ident = $value:expr $(=> $map:expr)?),* $(,)?
+    ) => {
+        impl $crate::entity::Output for $entity {
+            type ID = $id_type;
+
+            #[allow(unused_variables)]
+            fn to_lua(&self, $id: Option<Self::ID>) -> String {
+                let $this = self;
+                $crate::output::lua_entry(
+                    &$crate::lua_value!($key_kind $key),
+                    &[$((stringify!($field), $crate::lua_value!($kind $value $(=> $map)?))),*]
+                )
+            }
+
+            fn to_json(&self) -> String {
+                $crate::output::to_json(self)
+            }
+
+            $(
+                fn identity(&self) -> Option<String> {
+                    let $this = self;
+                    $identity
+                }
+            )?
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! lua_value {
+    (value $value:expr) => {
+        ($value).to_string()
+    };
+    (string $value:expr) => {
+        format!("\"{}\"", $value)
+    };
+    (bool $value:expr) => {
+        $crate::output::lua_bool($value).to_string()
+    };
+    (href $value:expr) => {
+        format!("\"{}\"", ($value).as_ref().and_then(|file_ref| file_ref.href.as_deref()).unwrap_or_default())
+    };
+    (list $value:expr) => {
+        $crate::output::lua_list(($value).iter().flatten(), |item| item.to_string())
+    };
+    (list $value:expr => $map:expr) => {
+        $crate::output::lua_list(($value).iter().flatten(), $map)
+    };
+}
+
+/// Formats entry of lua table, one field per line.
+pub fn lua_entry(key: &str, fields: &[(&str, String)]) -> String {
+    let fields = fields.iter()
+        .map(|(name, value)| format!("        {} = {}", name, value))
+        .collect::<Vec<String>>()
+        .join(",\n");
+    format!("\t[{}] = {{\n{}\n    }},\n", key, fields)
+}
+
+pub fn lua_bool(value: bool) -> &'static str {
+    if value {"1"} else {"nil"}
+}
+
+pub fn lua_list<I: IntoIterator>(items: I, map: impl Fn(I::Item) -> String) -> String {
+    let mut list = String::from("{");
+    for item in items {
+        list += &format!("{}, ", map(item));
+    }
+    list.push('}');
+    list
+}
+
+pub fn to_json<T: Serialize>(entity: &T) -> String {
+    serde_json::to_string_pretty(entity).unwrap()
+}
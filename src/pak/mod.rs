@@ -1,7 +1,8 @@
-use std::{path::PathBuf, collections::HashMap, ffi::OsString};
+use std::{path::PathBuf, collections::HashMap, ffi::OsString, ops::Deref};
 use std::io::Read;
 
 use rc_zip::{prelude::ReadZip};
+use crate::xdb::root_element;
 
 const IGNORED_PARTS: [&'static str; 35] = [
     "_(Model)/", "Characters/", "Arenas/", "_(AIGeometry)/", "_(BasicSkelAnim)/", "_(AnimSet)/", "_(CameraSet)/", 
@@ -63,4 +64,56 @@ pub fn check_pak(path: PathBuf, files: &mut HashMap<String, FileStructure>) {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Game files together with index of xdb files by their root element.
+/// Derefs to the file map itself, so it can be used anywhere the map is expected.
+#[derive(Debug, Default)]
+pub struct Vfs {
+    files: HashMap<String, FileStructure>,
+    roots: HashMap<String, Vec<String>>
+}
+
+impl Vfs {
+    pub fn from_files(files: HashMap<String, FileStructure>) -> Self {
+        let mut roots: HashMap<String, Vec<String>> = HashMap::new();
+        for (key, file) in files.iter().filter(|f| f.0.ends_with(".xdb")) {
+            if let Some(root) = root_element(&file.content) {
+                roots.entry(root).or_default().push(key.clone());
+            }
+        }
+        for keys in roots.values_mut() {
+            keys.sort();
+        }
+        Vfs { files, roots }
+    }
+
+    /// Files with given root element(like `AdvMapHeroShared` or `Creature`), sorted by key.
+    pub fn files_with_root(&self, root: &str) -> impl Iterator<Item = (&String, &FileStructure)> {
+        self.roots.get(root)
+            .into_iter()
+            .flatten()
+            .map(|key| (key, &self.files[key]))
+    }
+
+    pub fn into_files(self) -> HashMap<String, FileStructure> {
+        self.files
+    }
+}
+
+impl Deref for Vfs {
+    type Target = HashMap<String, FileStructure>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.files
+    }
+}
+
+/// Builds file map from several paks, newer entries override older ones like in game.
+pub fn load_paks(paths: &[PathBuf]) -> Vfs {
+    let mut files = HashMap::new();
+    for path in paths {
+        check_pak(path.clone(), &mut files);
+    }
+    Vfs::from_files(files)
+}
@@ -0,0 +1,435 @@
+//! Typed filter/sort/projection layer over scanned entities.
+//! Fields are addressed by their serde names, nested ones joined by dot, e.g. `Cost.Gold`.
+//! Query can be built in rust:
+//! `Query::<Creature>::new().filter(field("CreatureTier").eq(7)).filter_by(|c| c.Upgrade)`
+//! or parsed from string expression:
+//! `CreatureTown == TOWN_INFERNO and CreatureTier == 7 | sort Power desc | select CreatureTown, Power`
+//! Literals may be quoted, separators and operators inside quotes are part of literal: `Name == "Sword and Shield"`.
+
+use std::{cmp::Ordering, fmt};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Substring for strings, element for lists.
+    Contains
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc
+}
+
+#[derive(Debug)]
+pub struct QueryError(pub String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "query error: {}", self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub path: String,
+    pub op: Op,
+    pub value: Value
+}
+
+impl Condition {
+    pub fn new(path: &str, op: Op, value: impl Into<Value>) -> Self {
+        Condition { path: path.to_string(), op, value: value.into() }
+    }
+
+    pub fn matches(&self, entity: &Value) -> bool {
+        let actual = lookup(entity, &self.path);
+        match self.op {
+            Op::Eq => compare_values(actual, &self.value) == Some(Ordering::Equal),
+            Op::Ne => compare_values(actual, &self.value) != Some(Ordering::Equal),
+            Op::Lt => compare_values(actual, &self.value) == Some(Ordering::Less),
+            Op::Le => matches!(compare_values(actual, &self.value), Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => compare_values(actual, &self.value) == Some(Ordering::Greater),
+            Op::Ge => matches!(compare_values(actual, &self.value), Some(Ordering::Greater | Ordering::Equal)),
+            Op::Contains => match actual {
+                Value::Array(items) => items.iter().any(|item| compare_values(item, &self.value) == Some(Ordering::Equal)),
+                Value::String(text) => text.contains(&value_as_text(&self.value)),
+                _ => false
+            }
+        }
+    }
+
+    fn parse(expression: &str) -> Result<Self, QueryError> {
+        const OPERATORS: [(&str, Op); 8] = [
+            ("==", Op::Eq), ("!=", Op::Ne), (">=", Op::Ge), ("<=", Op::Le),
+            ("=", Op::Eq), (">", Op::Gt), ("<", Op::Lt), ("~", Op::Contains)
+        ];
+        // operator that appears first in expression wins, longer operators are checked before their prefixes
+        let found = OPERATORS.iter()
+            .filter_map(|(token, op)| find_unquoted(expression, token).map(|position| (position, *token, *op)))
+            .min_by_key(|(position, token, _)| (*position, usize::MAX - token.len()));
+        match found {
+            Some((position, token, op)) => {
+                let path = expression[..position].trim();
+                let value = expression[position + token.len()..].trim();
+                if path.is_empty() || value.is_empty() {
+                    return Err(QueryError(format!("incomplete condition `{}`", expression)));
+                }
+                Ok(Condition { path: path.to_string(), op, value: parse_literal(value) })
+            },
+            None => Err(QueryError(format!("no operator in condition `{}`", expression)))
+        }
+    }
+}
+
+/// Entry point for building conditions: `field("Level").ge(3)`.
+pub fn field(path: &str) -> Field {
+    Field(path.to_string())
+}
+
+pub struct Field(String);
+
+impl Field {
+    pub fn eq(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Eq, value) }
+    pub fn ne(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Ne, value) }
+    pub fn lt(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Lt, value) }
+    pub fn le(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Le, value) }
+    pub fn gt(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Gt, value) }
+    pub fn ge(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Ge, value) }
+    pub fn contains(self, value: impl Into<Value>) -> Condition { Condition::new(&self.0, Op::Contains, value) }
+}
+
+type Predicate<E> = Box<dyn Fn(&E) -> bool>;
+
+pub struct Query<E> {
+    conditions: Vec<Condition>,
+    predicates: Vec<Predicate<E>>,
+    sorting: Vec<(String, SortOrder)>,
+    projection: Vec<String>
+}
+
+impl<E: Serialize> Default for Query<E> {
+    fn default() -> Self {
+        Query { conditions: vec![], predicates: vec![], sorting: vec![], projection: vec![] }
+    }
+}
+
+impl<E: Serialize> Query<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses `cond and cond ... | sort path [asc|desc] | select path, path` expression.
+    /// Each stage is optional, conditions may be joined with `and` or `&&`.
+    pub fn parse(expression: &str) -> Result<Self, QueryError> {
+        let mut query = Self::new();
+        for stage in split_unquoted(expression, &["|"]).into_iter().map(str::trim).filter(|s| !s.is_empty()) {
+            if let Some(sorting) = stage.strip_prefix("sort ") {
+                let mut parts = sorting.split_whitespace();
+                let path = parts.next().ok_or_else(|| QueryError(format!("no field in `{}`", stage)))?;
+                let order = match parts.next() {
+                    None | Some("asc") => SortOrder::Asc,
+                    Some("desc") => SortOrder::Desc,
+                    Some(other) => return Err(QueryError(format!("unknown sort order `{}`", other)))
+                };
+                query = query.sort_by(path, order);
+            }
+            else if let Some(selection) = stage.strip_prefix("select ") {
+                let paths: Vec<&str> = selection.split(',').map(str::trim).filter(|p| !p.is_empty()).collect();
+                query = query.select(&paths);
+            }
+            else {
+                for condition in split_unquoted(stage, &[" and ", "&&"]).into_iter().map(str::trim).filter(|c| !c.is_empty()) {
+                    query = query.filter(Condition::parse(condition)?);
+                }
+            }
+        }
+        Ok(query)
+    }
+
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Adds typed predicate, checked against the entity itself instead of its serialized form.
+    pub fn filter_by(mut self, predicate: impl Fn(&E) -> bool + 'static) -> Self {
+        self.predicates.push(Box::new(predicate));
+        self
+    }
+
+    /// Sorting is stable, several calls sort by several keys in order of calls.
+    pub fn sort_by(mut self, path: &str, order: SortOrder) -> Self {
+        self.sorting.push((path.to_string(), order));
+        self
+    }
+
+    pub fn select(mut self, paths: &[&str]) -> Self {
+        self.projection.extend(paths.iter().map(|p| p.to_string()));
+        self
+    }
+
+    /// Returns entities matching query in requested order.
+    pub fn apply<'a>(&self, entities: impl IntoIterator<Item = &'a E>) -> Vec<&'a E> where E: 'a {
+        self.evaluate(entities).into_iter().map(|(entity, _)| entity).collect()
+    }
+
+    /// Same as `apply`, but returns selected fields only(or whole entities if nothing selected).
+    pub fn project<'a>(&self, entities: impl IntoIterator<Item = &'a E>) -> Vec<Value> where E: 'a {
+        self.evaluate(entities).into_iter()
+            .map(|(_, value)| {
+                if self.projection.is_empty() {
+                    value
+                }
+                else {
+                    let mut projected = Map::new();
+                    for path in &self.projection {
+                        projected.insert(path.clone(), lookup(&value, path).clone());
+                    }
+                    Value::Object(projected)
+                }
+            })
+            .collect()
+    }
+
+    fn evaluate<'a>(&self, entities: impl IntoIterator<Item = &'a E>) -> Vec<(&'a E, Value)> where E: 'a {
+        let mut matched: Vec<(&E, Value)> = entities.into_iter()
+            .filter(|entity| self.predicates.iter().all(|predicate| predicate(entity)))
+            .map(|entity| (entity, serde_json::to_value(entity).unwrap_or(Value::Null)))
+            .filter(|(_, value)| self.conditions.iter().all(|condition| condition.matches(value)))
+            .collect();
+        if !self.sorting.is_empty() {
+            matched.sort_by(|(_, first), (_, second)| {
+                self.sorting.iter()
+                    .map(|(path, order)| {
+                        compare_for_sort(lookup(first, path), lookup(second, path), *order)
+                    })
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+        matched
+    }
+}
+
+/// Byte positions of expression that are not inside quotes, quotes themselves are not included.
+fn unquoted_positions(expression: &str) -> Vec<usize> {
+    let mut positions = vec![];
+    let mut quote = None;
+    for (position, c) in expression.char_indices() {
+        match quote {
+            Some(opening) if c == opening => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => positions.push(position)
+        }
+    }
+    positions
+}
+
+/// Position of the first occurrence of token that starts outside quotes.
+fn find_unquoted(expression: &str, token: &str) -> Option<usize> {
+    unquoted_positions(expression).into_iter().find(|position| expression[*position..].starts_with(token))
+}
+
+/// Splits expression by any of separators, except ones inside quotes.
+fn split_unquoted<'a>(expression: &'a str, separators: &[&str]) -> Vec<&'a str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    for position in unquoted_positions(expression) {
+        if position < start {
+            continue;
+        }
+        if let Some(separator) = separators.iter().find(|separator| expression[position..].starts_with(*separator)) {
+            parts.push(&expression[start..position]);
+            start = position + separator.len();
+        }
+    }
+    parts.push(&expression[start..]);
+    parts
+}
+
+/// Resolves dot separated path in serialized entity, `Null` if any part is missing.
+pub fn lookup<'a>(value: &'a Value, path: &str) -> &'a Value {
+    let mut current = value;
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        current = match current {
+            Value::Object(fields) => fields.get(part).unwrap_or(&Value::Null),
+            Value::Array(items) => part.parse::<usize>().ok().and_then(|i| items.get(i)).unwrap_or(&Value::Null),
+            _ => &Value::Null
+        };
+    }
+    current
+}
+
+fn parse_literal(text: &str) -> Value {
+    let quoted = text.len() >= 2 && ((text.starts_with('"') && text.ends_with('"')) || (text.starts_with('\'') && text.ends_with('\'')));
+    if quoted {
+        return Value::String(text[1..text.len() - 1].to_string());
+    }
+    match text {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "null" | "nil" => Value::Null,
+        _ => serde_json::from_str::<serde_json::Number>(text)
+            .map(Value::Number)
+            .unwrap_or_else(|_| Value::String(text.to_string()))
+    }
+}
+
+fn value_as_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string()
+    }
+}
+
+/// Number or string holding a number, like `"9"` or text of xdb element.
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None
+    }
+}
+
+/// Numbers and strings holding numbers are compared as numbers, so `"9"` is less than `10`.
+fn compare_values(first: &Value, second: &Value) -> Option<Ordering> {
+    if let (Some(first), Some(second)) = (as_number(first), as_number(second)) {
+        return first.partial_cmp(&second);
+    }
+    match (first, second) {
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::Null, _) | (_, Value::Null) => None,
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Array(_) | Value::Object(_), _) | (_, Value::Array(_) | Value::Object(_)) => {
+            (first == second).then_some(Ordering::Equal)
+        },
+        // e.g. enum name stored as string compared to number from expression
+        _ => Some(value_as_text(first).cmp(&value_as_text(second)))
+    }
+}
+
+/// Missing values go last regardless of type.
+fn compare_for_sort(first: &Value, second: &Value, order: SortOrder) -> Ordering {
+    match (first.is_null(), second.is_null()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => {
+            let ordering = compare_values(first, second).unwrap_or(Ordering::Equal);
+            if order == SortOrder::Desc { ordering.reverse() } else { ordering }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    #[allow(non_snake_case)]
+    struct Item {
+        Name: String,
+        Level: u8,
+        /// Number kept as text, like values of configured entities.
+        Code: String,
+        Tags: Vec<String>
+    }
+
+    fn item(name: &str, level: u8, code: &str, tags: &[&str]) -> Item {
+        Item { Name: name.to_string(), Level: level, Code: code.to_string(), Tags: tags.iter().map(|tag| tag.to_string()).collect() }
+    }
+
+    fn items() -> Vec<Item> {
+        vec![
+            item("Sword and Shield", 1, "10", &["weapon", "armor"]),
+            item("Bow", 3, "9", &["weapon"]),
+            item("Helm | Crown", 2, "100", &["armor"]),
+            item("Ring", 5, "1", &[])
+        ]
+    }
+
+    fn names(query: &Query<Item>, items: &[Item]) -> Vec<String> {
+        query.apply(items).into_iter().map(|item| item.Name.clone()).collect()
+    }
+
+    #[test]
+    fn parses_conditions_sorting_and_selection() {
+        let items = items();
+        let query = Query::<Item>::parse("Level >= 2 and Level < 5 | sort Level desc | select Name, Level").unwrap();
+        assert_eq!(names(&query, &items), ["Bow", "Helm | Crown"]);
+        let projected = query.project(&items);
+        assert_eq!(projected[0], serde_json::json!({"Name": "Bow", "Level": 3}));
+    }
+
+    #[test]
+    fn ampersands_join_conditions() {
+        let items = items();
+        let query = Query::<Item>::parse("Level > 1 && Tags ~ weapon").unwrap();
+        assert_eq!(names(&query, &items), ["Bow"]);
+    }
+
+    #[test]
+    fn separators_inside_quotes_are_part_of_literal() {
+        let items = items();
+        let query = Query::<Item>::parse("Name == \"Sword and Shield\"").unwrap();
+        assert_eq!(names(&query, &items), ["Sword and Shield"]);
+        let query = Query::<Item>::parse("Name == 'Helm | Crown' | select Level").unwrap();
+        assert_eq!(query.project(&items), [serde_json::json!({"Level": 2})]);
+        let query = Query::<Item>::parse("Name ~ \"d and S\" && Level == 1").unwrap();
+        assert_eq!(names(&query, &items), ["Sword and Shield"]);
+    }
+
+    #[test]
+    fn operators_inside_quotes_are_part_of_literal() {
+        let condition = Condition::parse("Name == \"a >= b\"").unwrap();
+        assert_eq!(condition.path, "Name");
+        assert_eq!(condition.op, Op::Eq);
+        assert_eq!(condition.value, Value::String("a >= b".to_string()));
+    }
+
+    #[test]
+    fn numeric_strings_are_compared_as_numbers() {
+        let items = items();
+        let query = Query::<Item>::parse("Code > 9").unwrap();
+        assert_eq!(names(&query, &items), ["Sword and Shield", "Helm | Crown"]);
+        let query = Query::<Item>::parse("Level < \"10\"").unwrap();
+        assert_eq!(query.apply(&items).len(), 4);
+        let query = Query::<Item>::new().sort_by("Code", SortOrder::Asc);
+        assert_eq!(names(&query, &items), ["Ring", "Bow", "Sword and Shield", "Helm | Crown"]);
+    }
+
+    #[test]
+    fn missing_values_are_sorted_last() {
+        let items = items();
+        let query = Query::<Item>::parse("| sort Missing | sort Level desc").unwrap();
+        assert_eq!(names(&query, &items), ["Ring", "Bow", "Helm | Crown", "Sword and Shield"]);
+    }
+
+    #[test]
+    fn builder_combines_conditions_and_predicates() {
+        let items = items();
+        let query = Query::<Item>::new()
+            .filter(field("Tags").contains("armor"))
+            .filter_by(|item| item.Level > 1);
+        assert_eq!(names(&query, &items), ["Helm | Crown"]);
+    }
+
+    #[test]
+    fn incomplete_conditions_are_errors() {
+        assert!(Query::<Item>::parse("Level").is_err());
+        assert!(Query::<Item>::parse("== 3").is_err());
+        assert!(Query::<Item>::parse("Name == \"a\" | sort Level sideways").is_err());
+    }
+}
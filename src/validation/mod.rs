@@ -0,0 +1,435 @@
+//! Rule based validation of scanned entities.
+//! `Validator::new()` contains built-in rules, custom ones are added with `Validator::add_rule`.
+
+use std::{collections::HashMap, fmt};
+use serde::Serialize;
+use homm5_types::common::FileRef;
+use crate::{
+    entity::{
+        art::{Artifact, ARTIFACTS_REFTABLE}, art_processor, creature::{Creature, CREATURES_REFTABLE}, creature_processor,
+        hero::Hero, hero_processor, read_reftable, spell::{Spell, SPELLS_REFTABLE}, spell_processor, ScannedEntity
+    },
+    pak::Vfs,
+    xdb::resolve_href
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Severity {
+    Warning,
+    Error
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub rule: String,
+    pub severity: Severity,
+    pub file_key: String,
+    pub pak: String,
+    /// Scaner id of entity, needed when several entities share one file.
+    pub id: Option<String>,
+    pub message: String
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.id {
+            Some(id) => write!(f, "[{:?}] {}: {}#{} ({}): {}", self.severity, self.rule, self.file_key, id, self.pak, self.message),
+            None => write!(f, "[{:?}] {}: {} ({}): {}", self.severity, self.rule, self.file_key, self.pak, self.message)
+        }
+    }
+}
+
+/// Everything rules can look at: scanned entities of all built-in tables, reftables ids and files themselves.
+pub struct ValidationContext<'a> {
+    pub files: &'a Vfs,
+    pub creatures: Vec<ScannedEntity<u16>>,
+    pub artifacts: Vec<ScannedEntity<u16>>,
+    pub spells: Vec<ScannedEntity<u16>>,
+    pub heroes: Vec<ScannedEntity<String>>,
+    /// Ids of reftables in order they are listed, with keys of files they point to.
+    pub creature_ids: Vec<(String, Option<String>)>,
+    pub spell_ids: Vec<(String, Option<String>)>,
+    /// Artifacts are inline, so they have no file keys.
+    pub artifact_ids: Vec<(String, Option<String>)>
+}
+
+impl<'a> ValidationContext<'a> {
+    pub fn scan(files: &'a Vfs) -> Self {
+        ValidationContext {
+            files,
+            creatures: creature_processor().scan(files),
+            artifacts: art_processor().scan(files),
+            spells: spell_processor().scan(files),
+            heroes: hero_processor().scan(files),
+            creature_ids: read_reftable(files, CREATURES_REFTABLE),
+            spell_ids: read_reftable(files, SPELLS_REFTABLE),
+            artifact_ids: read_reftable(files, ARTIFACTS_REFTABLE)
+        }
+    }
+
+    /// Scanned creature by its reftable id, like `CREATURE_PEASANT`.
+    pub fn creature_by_id(&self, id: &str) -> Option<&Creature> {
+        let key = self.creature_ids.iter().find(|(creature_id, _)| creature_id == id)?.1.as_ref()?;
+        self.creatures.iter()
+            .find(|creature| &creature.file_key == key)
+            .and_then(|creature| creature.get::<Creature>())
+    }
+
+    /// Checks that reference points to existing file, either by absolute key or relative to referencing file.
+    pub fn reference_exists(&self, reference: Option<&FileRef>, file_key: &str) -> bool {
+        reference
+            .and_then(|r| r.href.as_ref())
+            .and_then(|href| resolve_href(href, file_key, self.files))
+            .is_some()
+    }
+}
+
+pub trait Rule {
+    fn name(&self) -> &str;
+    fn check(&self, context: &ValidationContext, violations: &mut Vec<Violation>);
+}
+
+pub struct Validator {
+    rules: Vec<Box<dyn Rule>>
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator {
+            rules: vec![
+                Box::new(MissingTextRule {}),
+                Box::new(MissingIconRule {}),
+                Box::new(BrokenReferenceRule {}),
+                Box::new(StatRangeRule {}),
+                Box::new(DuplicateIdRule {})
+            ]
+        }
+    }
+}
+
+impl Validator {
+    /// Validator with all built-in rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Validator with no rules at all.
+    pub fn empty() -> Self {
+        Validator { rules: vec![] }
+    }
+
+    pub fn add_rule(&mut self, rule: Box<dyn Rule>) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn run(&self, context: &ValidationContext) -> Vec<Violation> {
+        let mut violations = vec![];
+        for rule in &self.rules {
+            rule.check(context, &mut violations);
+        }
+        violations
+    }
+}
+
+/// Shortcut for rules reporting problem of scanned entity.
+pub fn violation<T: ToString>(rule: &str, severity: Severity, entity: &ScannedEntity<T>, message: String) -> Violation {
+    Violation {
+        rule: rule.to_string(),
+        severity,
+        file_key: entity.file_key.clone(),
+        pak: entity.pak.clone(),
+        id: entity.id.as_ref().map(|id| id.to_string()),
+        message
+    }
+}
+
+fn check_reference<T: ToString>(
+    rule: &str,
+    context: &ValidationContext,
+    reference: Option<&FileRef>,
+    entity: &ScannedEntity<T>,
+    message: impl FnOnce() -> String,
+    violations: &mut Vec<Violation>
+) {
+    if !context.reference_exists(reference, &entity.file_key) {
+        violations.push(violation(rule, Severity::Warning, entity, message()));
+    }
+}
+
+fn typed<T: 'static, E: 'static>(entities: &[ScannedEntity<T>]) -> impl Iterator<Item = (&ScannedEntity<T>, &E)> {
+    entities.iter().filter_map(|entity| entity.get::<E>().map(|typed| (entity, typed)))
+}
+
+/// Names and descriptions must point to existing txt files.
+pub struct MissingTextRule {}
+
+impl Rule for MissingTextRule {
+    fn name(&self) -> &str {
+        "missing_text"
+    }
+
+    fn check(&self, context: &ValidationContext, violations: &mut Vec<Violation>) {
+        for (entity, creature) in typed::<_, Creature>(&context.creatures) {
+            let visual = creature.VisualExplained.as_ref();
+            check_reference(self.name(), context, visual.and_then(|v| v.CreatureNameFileRef.as_ref()), entity, || "creature has no name".to_string(), violations);
+            check_reference(self.name(), context, visual.and_then(|v| v.DescriptionFileRef.as_ref()), entity, || "creature has no description".to_string(), violations);
+        }
+        for (entity, spell) in typed::<_, Spell>(&context.spells) {
+            check_reference(self.name(), context, spell.NameFileRef.as_ref(), entity, || "spell has no name".to_string(), violations);
+            check_reference(self.name(), context, spell.LongDescriptionFileRef.as_ref(), entity, || "spell has no description".to_string(), violations);
+        }
+        for (entity, art) in typed::<_, Artifact>(&context.artifacts) {
+            check_reference(self.name(), context, art.NameFileRef.as_ref(), entity, || "artifact has no name".to_string(), violations);
+            check_reference(self.name(), context, art.DescriptionFileRef.as_ref(), entity, || "artifact has no description".to_string(), violations);
+        }
+        for (entity, hero) in typed::<_, Hero>(&context.heroes) {
+            check_reference(self.name(), context, hero.Editable.NameFileRef.as_ref(), entity, || format!("hero {} has no name", hero.InternalName), violations);
+            check_reference(self.name(), context, hero.SpecializationNameFileRef.as_ref(), entity, || format!("hero {} has no specialization name", hero.InternalName), violations);
+        }
+    }
+}
+
+/// Icons must point to existing textures.
+pub struct MissingIconRule {}
+
+impl Rule for MissingIconRule {
+    fn name(&self) -> &str {
+        "missing_icon"
+    }
+
+    fn check(&self, context: &ValidationContext, violations: &mut Vec<Violation>) {
+        for (entity, creature) in typed::<_, Creature>(&context.creatures) {
+            check_reference(self.name(), context, creature.VisualExplained.as_ref().and_then(|v| v.Icon128.as_ref()), entity, || "creature has no icon".to_string(), violations);
+        }
+        for (entity, spell) in typed::<_, Spell>(&context.spells) {
+            check_reference(self.name(), context, spell.Texture.as_ref(), entity, || "spell has no icon".to_string(), violations);
+        }
+        for (entity, art) in typed::<_, Artifact>(&context.artifacts) {
+            check_reference(self.name(), context, art.Icon.as_ref(), entity, || "artifact has no icon".to_string(), violations);
+        }
+        for (entity, hero) in typed::<_, Hero>(&context.heroes) {
+            check_reference(self.name(), context, hero.FaceTexture.as_ref(), entity, || format!("hero {} has no face texture", hero.InternalName), violations);
+            check_reference(self.name(), context, hero.SpecializationIcon.as_ref(), entity, || format!("hero {} has no specialization icon", hero.InternalName), violations);
+        }
+    }
+}
+
+/// Creature ids used by creatures must exist, upgrades must be upgrades and base creatures must not.
+/// Spells known by creatures must exist.
+pub struct BrokenReferenceRule {}
+
+impl BrokenReferenceRule {
+    fn is_empty_id(id: &str) -> bool {
+        id.is_empty() || id == "CREATURE_UNKNOWN" || id == "SPELL_NONE"
+    }
+}
+
+impl Rule for BrokenReferenceRule {
+    fn name(&self) -> &str {
+        "broken_reference"
+    }
+
+    fn check(&self, context: &ValidationContext, violations: &mut Vec<Violation>) {
+        for (entity, creature) in typed::<_, Creature>(&context.creatures) {
+            if !Self::is_empty_id(&creature.PairCreature) && context.creature_by_id(&creature.PairCreature).is_none() {
+                violations.push(violation(self.name(), Severity::Error, entity,
+                    format!("pair creature {} does not exist", creature.PairCreature)));
+            }
+            if let Some(base) = creature.BaseCreature.as_ref().filter(|base| !Self::is_empty_id(base)) {
+                match context.creature_by_id(base) {
+                    Some(base_creature) if base_creature.Upgrade => violations.push(violation(self.name(), Severity::Error, entity,
+                        format!("base creature {} is an upgrade itself", base))),
+                    Some(_) => {},
+                    None => violations.push(violation(self.name(), Severity::Error, entity,
+                        format!("base creature {} does not exist", base)))
+                }
+            }
+            for upgrade in creature.upgrades.iter().flatten().filter(|upgrade| !Self::is_empty_id(upgrade)) {
+                match context.creature_by_id(upgrade) {
+                    Some(upgrade_creature) if !upgrade_creature.Upgrade => violations.push(violation(self.name(), Severity::Error, entity,
+                        format!("upgrade {} is not an upgrade", upgrade))),
+                    Some(_) => {},
+                    None => violations.push(violation(self.name(), Severity::Error, entity,
+                        format!("upgrade {} does not exist", upgrade)))
+                }
+            }
+            for spell in creature.KnownSpells.spells.iter().flatten().filter(|spell| !Self::is_empty_id(&spell.Spell)) {
+                if !context.spell_ids.iter().any(|(id, _)| id == &spell.Spell) {
+                    violations.push(violation(self.name(), Severity::Error, entity,
+                        format!("known spell {} does not exist", spell.Spell)));
+                }
+            }
+        }
+    }
+}
+
+/// Stats that can't be correct in game.
+pub struct StatRangeRule {}
+
+impl Rule for StatRangeRule {
+    fn name(&self) -> &str {
+        "stat_range"
+    }
+
+    fn check(&self, context: &ValidationContext, violations: &mut Vec<Violation>) {
+        for (entity, creature) in typed::<_, Creature>(&context.creatures) {
+            if creature.MinDamage > creature.MaxDamage {
+                violations.push(violation(self.name(), Severity::Error, entity,
+                    format!("min damage {} is greater than max damage {}", creature.MinDamage, creature.MaxDamage)));
+            }
+            if creature.Health == 0 {
+                violations.push(violation(self.name(), Severity::Error, entity, "creature has zero health".to_string()));
+            }
+            if creature.CreatureTier > 7 {
+                violations.push(violation(self.name(), Severity::Error, entity, format!("creature tier {} is out of range", creature.CreatureTier)));
+            }
+        }
+        for (entity, spell) in typed::<_, Spell>(&context.spells) {
+            if spell.Level > 5 {
+                violations.push(violation(self.name(), Severity::Error, entity, format!("spell level {} is out of range", spell.Level)));
+            }
+        }
+        for (entity, art) in typed::<_, Artifact>(&context.artifacts) {
+            if art.CostOfGold == 0 {
+                violations.push(violation(self.name(), Severity::Warning, entity, "artifact costs nothing".to_string()));
+            }
+        }
+    }
+}
+
+/// Reftable ids and hero internal names must be unique.
+pub struct DuplicateIdRule {}
+
+impl DuplicateIdRule {
+    fn check_reftable(&self, reftable: &str, ids: &[(String, Option<String>)], context: &ValidationContext, violations: &mut Vec<Violation>) {
+        let mut seen = HashMap::new();
+        for (id, _) in ids {
+            *seen.entry(id).or_insert(0) += 1;
+            if seen[id] == 2 {
+                violations.push(Violation {
+                    rule: self.name().to_string(),
+                    severity: Severity::Error,
+                    file_key: reftable.to_lowercase(),
+                    pak: context.files.get(&reftable.to_lowercase()).map(|f| f.pak.clone()).unwrap_or_default(),
+                    id: Some(id.clone()),
+                    message: format!("id {} is listed more than once", id)
+                });
+            }
+        }
+    }
+}
+
+impl Rule for DuplicateIdRule {
+    fn name(&self) -> &str {
+        "duplicate_id"
+    }
+
+    fn check(&self, context: &ValidationContext, violations: &mut Vec<Violation>) {
+        self.check_reftable(CREATURES_REFTABLE, &context.creature_ids, context, violations);
+        self.check_reftable(SPELLS_REFTABLE, &context.spell_ids, context, violations);
+        self.check_reftable(ARTIFACTS_REFTABLE, &context.artifact_ids, context, violations);
+        let mut first_of: HashMap<&String, &ScannedEntity<String>> = HashMap::new();
+        for (entity, hero) in typed::<_, Hero>(&context.heroes) {
+            match first_of.get(&hero.InternalName) {
+                Some(first) => violations.push(violation(self.name(), Severity::Error, entity,
+                    format!("hero internal name {} is already used by {}", hero.InternalName, first.file_key))),
+                None => {
+                    first_of.insert(&hero.InternalName, entity);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pak::FileStructure;
+
+    fn files(documents: &[(&str, String)]) -> Vfs {
+        Vfs::from_files(documents.iter()
+            .map(|(key, content)| (key.to_string(), FileStructure { pak: "data.pak".to_string(), modified: 0, content: content.clone() }))
+            .collect())
+    }
+
+    fn creature(min_damage: u16, max_damage: u16, is_upgrade: bool, upgrades: &[&str]) -> String {
+        let upgrades: String = upgrades.iter().map(|upgrade| format!("<Item>{}</Item>", upgrade)).collect();
+        format!(r#"<Creature>
+            <AttackSkill>1</AttackSkill><DefenceSkill>1</DefenceSkill><MinDamage>{min_damage}</MinDamage><MaxDamage>{max_damage}</MaxDamage>
+            <Speed>4</Speed><Initiative>8</Initiative><Flying>false</Flying><Health>3</Health><KnownSpells/><SpellPoints>0</SpellPoints>
+            <Exp>5</Exp><Power>50</Power><CreatureTier>1</CreatureTier><Upgrade>{is_upgrade}</Upgrade><PairCreature>CREATURE_UNKNOWN</PairCreature>
+            <CreatureTown>TOWN_HEAVEN</CreatureTown><MagicElement><First>ELEMENT_NONE</First><Second>ELEMENT_NONE</Second></MagicElement>
+            <WeeklyGrowth>20</WeeklyGrowth><Cost><Wood>0</Wood><Ore>0</Ore><Mercury>0</Mercury><Crystal>0</Crystal><Sulfur>0</Sulfur><Gem>0</Gem><Gold>15</Gold></Cost>
+            <SubjectOfRandomGeneration>true</SubjectOfRandomGeneration><CombatSize>1</CombatSize><Range>0</Range>
+            <BaseCreature>CREATURE_UNKNOWN</BaseCreature><Upgrades>{upgrades}</Upgrades><Abilities/>
+        </Creature>"#)
+    }
+
+    fn reftable(items: &[(&str, &str)]) -> String {
+        let items: String = items.iter()
+            .map(|(id, key)| format!(r#"<Item><ID>{}</ID><Obj href="/{}#xpointer(/Creature)"/></Item>"#, id, key))
+            .collect();
+        format!("<Table><objects>{}</objects></Table>", items)
+    }
+
+    fn messages(rule: impl Rule + 'static, files: &Vfs) -> Vec<String> {
+        let mut validator = Validator::empty();
+        validator.add_rule(Box::new(rule));
+        validator.run(&ValidationContext::scan(files)).into_iter().map(|violation| violation.message).collect()
+    }
+
+    #[test]
+    fn min_damage_above_max_damage() {
+        let files = files(&[
+            ("gamemechanics/reftables/creatures.xdb", reftable(&[("CREATURE_PEASANT", "peasant.xdb"), ("CREATURE_ARCHER", "archer.xdb")])),
+            ("peasant.xdb", creature(3, 1, false, &[])),
+            ("archer.xdb", creature(2, 2, false, &[]))
+        ]);
+        assert_eq!(messages(StatRangeRule {}, &files), ["min damage 3 is greater than max damage 1"]);
+    }
+
+    #[test]
+    fn upgrade_that_is_not_upgrade() {
+        let files = files(&[
+            ("gamemechanics/reftables/creatures.xdb", reftable(&[
+                ("CREATURE_PEASANT", "peasant.xdb"),
+                ("CREATURE_MILITIAMAN", "militiaman.xdb"),
+                ("CREATURE_ARCHER", "archer.xdb")
+            ])),
+            ("peasant.xdb", creature(1, 1, false, &["CREATURE_MILITIAMAN", "CREATURE_ARCHER"])),
+            ("militiaman.xdb", creature(1, 2, true, &[])),
+            ("archer.xdb", creature(2, 2, false, &[]))
+        ]);
+        assert_eq!(messages(BrokenReferenceRule {}, &files), ["upgrade CREATURE_ARCHER is not an upgrade"]);
+    }
+
+    #[test]
+    fn duplicate_reftable_ids() {
+        let files = files(&[
+            ("gamemechanics/reftables/creatures.xdb", reftable(&[("CREATURE_PEASANT", "peasant.xdb"), ("CREATURE_PEASANT", "peasant.xdb")])),
+            ("peasant.xdb", creature(1, 1, false, &[])),
+            ("gamemechanics/reftables/artifacts.xdb", "<Table><objects>
+                <Item><ID>ARTIFACT_SWORD</ID><obj/></Item><Item><ID>ARTIFACT_RING</ID><obj/></Item><Item><ID>ARTIFACT_SWORD</ID><obj/></Item>
+            </objects></Table>".to_string())
+        ]);
+        assert_eq!(messages(DuplicateIdRule {}, &files), [
+            "id CREATURE_PEASANT is listed more than once",
+            "id ARTIFACT_SWORD is listed more than once"
+        ]);
+    }
+
+    #[test]
+    fn spell_without_texture_has_no_icon() {
+        let files = files(&[
+            ("gamemechanics/reftables/undividedspells.xdb", "<Table><objects>
+                <Item><ID>SPELL_MAGIC_ARROW</ID><Obj href=\"/arrow.xdb#xpointer(/Spell)\"/></Item>
+            </objects></Table>".to_string()),
+            ("arrow.xdb", "<Spell><NameFileRef href=\"Name.txt\"/><Level>1</Level><MagicSchool>MAGIC_SCHOOL_DESTRUCTIVE</MagicSchool>
+                <IsAimed>true</IsAimed><IsAreaAttack>false</IsAreaAttack></Spell>".to_string()),
+            ("name.txt", "Magic arrow".to_string())
+        ]);
+        assert_eq!(messages(MissingIconRule {}, &files), ["spell has no icon"]);
+        assert_eq!(messages(MissingTextRule {}, &files), ["spell has no description"]);
+    }
+}
@@ -0,0 +1,265 @@
+//! Untyped view of xdb files as `serde_json::Value` trees.
+//! Elements become objects keeping order of children, attributes are stored with `@` prefix(`@href`),
+//! elements with text only become strings and lists of `<Item>` become arrays.
+//! References can be followed: referenced xdb is put next to `@href` as `@resolved`.
+
+use std::collections::{HashMap, HashSet};
+use quick_xml::{Reader, events::{BytesStart, Event}};
+use serde_json::{Map, Value};
+use crate::pak::FileStructure;
+
+/// Name of the first element of xdb, like `Creature` or `AdvMapHeroShared`.
+pub fn root_element(content: &str) -> Option<String> {
+    let mut reader = Reader::from_str(content);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => break Some(String::from_utf8_lossy(e.name().as_ref()).to_string()),
+            Ok(Event::Eof) | Err(_) => break None,
+            _ => ()
+        }
+    }
+}
+
+/// Converts whole document to object with single field named as root element.
+pub fn document_to_value(content: &str) -> Option<Value> {
+    let root = root_element(content)?;
+    let value = element_to_value(content, &root)?;
+    let mut document = Map::new();
+    document.insert(root, value);
+    Some(Value::Object(document))
+}
+
+/// Converts file of the game, following references to other xdb files up to `depth` levels.
+/// Reference leading back to file that is being expanded is marked with `@cycle` instead.
+pub fn file_to_value(file_key: &str, files: &HashMap<String, FileStructure>, depth: usize) -> Option<Value> {
+    let file_key = file_key.trim_start_matches('/').to_lowercase();
+    let mut value = document_to_value(&files.get(&file_key)?.content)?;
+    let mut chain = vec![file_key.clone()];
+    follow_references(&mut value, &file_key, files, depth, &mut chain);
+    Some(value)
+}
+
+/// Key of file reference points to, either absolute or relative to referencing file.
+pub fn resolve_href(href: &str, file_key: &str, files: &HashMap<String, FileStructure>) -> Option<String> {
+    let path = href.split('#').next().unwrap_or_default().trim_start_matches('/').to_lowercase();
+    if path.is_empty() {
+        return None;
+    }
+    if files.contains_key(&path) {
+        return Some(path);
+    }
+    let relative = format!("{}/{}", file_key.rsplit_once('/')?.0, path);
+    files.contains_key(&relative).then_some(relative)
+}
+
+fn follow_references(value: &mut Value, file_key: &str, files: &HashMap<String, FileStructure>, depth: usize, chain: &mut Vec<String>) {
+    match value {
+        Value::Object(fields) => {
+            for field in fields.values_mut() {
+                follow_references(field, file_key, files, depth, chain);
+            }
+            if depth == 0 {
+                return;
+            }
+            let target = fields.get("@href")
+                .and_then(Value::as_str)
+                .and_then(|href| resolve_href(href, file_key, files))
+                .filter(|target| target.ends_with(".xdb"));
+            if let Some(target) = target {
+                if chain.contains(&target) {
+                    fields.insert("@cycle".to_string(), Value::Bool(true));
+                }
+                else if let Some(mut resolved) = document_to_value(&files[&target].content) {
+                    chain.push(target.clone());
+                    follow_references(&mut resolved, &target, files, depth - 1, chain);
+                    chain.pop();
+                    fields.insert("@resolved".to_string(), resolved);
+                }
+            }
+        },
+        Value::Array(items) => {
+            for item in items {
+                follow_references(item, file_key, files, depth, chain);
+            }
+        },
+        _ => ()
+    }
+}
+
+/// Converts first element with given name found in content.
+pub fn element_to_value(content: &str, tag: &str) -> Option<Value> {
+    let mut reader = Reader::from_str(content);
+    reader.trim_text(true);
+    reader.expand_empty_elements(true);
+    loop {
+        match reader.read_event() {
+            Err(e) => {
+                crate::diagnostic!("Error at position {}: {:?}", reader.buffer_position(), e);
+                break None;
+            },
+            Ok(Event::Eof) => break None,
+            Ok(Event::Start(e)) if e.name().as_ref() == tag.as_bytes() => {
+                match read_element(&mut reader, &e) {
+                    Ok(value) => break Some(value),
+                    Err(e) => {
+                        crate::diagnostic!("Error reading element {}: {:?}", tag, e);
+                        break None;
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Converts element whose start tag was just read, leaving reader after its end tag.
+/// Reader must be created with `expand_empty_elements(true)`.
+pub fn read_element(reader: &mut Reader<&[u8]>, start: &BytesStart) -> Result<Value, quick_xml::Error> {
+    let mut attributes = Map::new();
+    for attribute in start.attributes().flatten() {
+        let name = format!("@{}", String::from_utf8_lossy(attribute.key.as_ref()));
+        attributes.insert(name, Value::String(attribute.unescape_value()?.to_string()));
+    }
+    let mut children: Vec<(String, Value)> = vec![];
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let child = read_element(reader, &e)?;
+                children.push((String::from_utf8_lossy(e.name().as_ref()).to_string(), child));
+            },
+            Event::Text(e) => text += &e.unescape()?,
+            Event::CData(e) => text += &String::from_utf8_lossy(&e),
+            Event::End(_) => break,
+            Event::Eof => return Err(quick_xml::Error::UnexpectedEof(String::from_utf8_lossy(start.name().as_ref()).to_string())),
+            _ => ()
+        }
+    }
+    if children.is_empty() && attributes.is_empty() {
+        return Ok(Value::String(text));
+    }
+    if attributes.is_empty() && children.iter().all(|(name, _)| name == "Item") {
+        return Ok(Value::Array(children.into_iter().map(|(_, child)| child).collect()));
+    }
+    let mut object = attributes;
+    // names of children already met several times, their values are arrays now
+    let mut repeated = HashSet::new();
+    for (name, child) in children {
+        match object.get_mut(&name) {
+            Some(Value::Array(items)) if repeated.contains(&name) => items.push(child),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, child]);
+                repeated.insert(name);
+            },
+            None => {
+                object.insert(name, child);
+            }
+        }
+    }
+    if !text.is_empty() {
+        object.insert("$text".to_string(), Value::String(text));
+    }
+    Ok(Value::Object(object))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn files(documents: &[(&str, &str)]) -> HashMap<String, FileStructure> {
+        documents.iter()
+            .map(|(key, content)| (key.to_string(), FileStructure { pak: "data.pak".to_string(), modified: 0, content: content.to_string() }))
+            .collect()
+    }
+
+    /// Reads element with given name the way `element_to_value` does.
+    fn read(content: &str, tag: &str) -> Value {
+        let mut reader = Reader::from_str(content);
+        reader.trim_text(true);
+        reader.expand_empty_elements(true);
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) if e.name().as_ref() == tag.as_bytes() => break read_element(&mut reader, &e).unwrap(),
+                Event::Eof => panic!("no element {}", tag),
+                _ => ()
+            }
+        }
+    }
+
+    #[test]
+    fn items_become_arrays() {
+        assert_eq!(read("<List><Item>1</Item><Item>2</Item></List>", "List"), json!(["1", "2"]));
+        assert_eq!(read("<List><Item>1</Item></List>", "List"), json!(["1"]));
+        assert_eq!(
+            read("<List><Item><ID>A</ID></Item><Item><ID>B</ID></Item></List>", "List"),
+            json!([{"ID": "A"}, {"ID": "B"}])
+        );
+    }
+
+    #[test]
+    fn repeated_children_become_arrays() {
+        assert_eq!(
+            read("<Spell><School>A</School><Level>1</Level><School>B</School><School>C</School></Spell>", "Spell"),
+            json!({"School": ["A", "B", "C"], "Level": "1"})
+        );
+    }
+
+    #[test]
+    fn attributes_and_text_are_kept() {
+        assert_eq!(read(r#"<Root><Icon href="/Textures/Icon.xdb#xpointer(/Texture)"/></Root>"#, "Root"), json!({"Icon": {"@href": "/Textures/Icon.xdb#xpointer(/Texture)"}}));
+        assert_eq!(read(r#"<Name lang="en">Sword</Name>"#, "Name"), json!({"@lang": "en", "$text": "Sword"}));
+        assert_eq!(read("<Root><Empty/></Root>", "Root"), json!({"Empty": ""}));
+        // items with attributes are not a list
+        assert_eq!(read(r#"<List id="1"><Item>1</Item></List>"#, "List"), json!({"@id": "1", "Item": "1"}));
+    }
+
+    #[test]
+    fn resolves_absolute_and_relative_hrefs() {
+        let files = files(&[("gamemechanics/spell/bless.xdb", "<Spell/>"), ("gamemechanics/spell/name.txt", "Bless")]);
+        assert_eq!(resolve_href("/GameMechanics/Spell/Bless.xdb#xpointer(/Spell)", "any/file.xdb", &files).as_deref(), Some("gamemechanics/spell/bless.xdb"));
+        assert_eq!(resolve_href("Name.txt", "gamemechanics/spell/bless.xdb", &files).as_deref(), Some("gamemechanics/spell/name.txt"));
+        assert_eq!(resolve_href("Missing.txt", "gamemechanics/spell/bless.xdb", &files), None);
+        assert_eq!(resolve_href("#n:inline(Texture)", "gamemechanics/spell/bless.xdb", &files), None);
+    }
+
+    #[test]
+    fn references_are_followed_up_to_depth() {
+        let files = files(&[
+            ("a.xdb", r#"<A><Next href="b.xdb#xpointer(/B)"/></A>"#),
+            ("b.xdb", r#"<B><Next href="/C.xdb"/></B>"#),
+            ("c.xdb", "<C><Value>3</Value></C>")
+        ]);
+        let shallow = file_to_value("A.xdb", &files, 0).unwrap();
+        assert_eq!(shallow, json!({"A": {"Next": {"@href": "b.xdb#xpointer(/B)"}}}));
+        let one_level = file_to_value("a.xdb", &files, 1).unwrap();
+        assert_eq!(one_level["A"]["Next"]["@resolved"], json!({"B": {"Next": {"@href": "/C.xdb"}}}));
+        let deep = file_to_value("a.xdb", &files, 5).unwrap();
+        assert_eq!(deep["A"]["Next"]["@resolved"]["B"]["Next"]["@resolved"], json!({"C": {"Value": "3"}}));
+    }
+
+    #[test]
+    fn cycles_are_marked_instead_of_followed() {
+        let files = files(&[
+            ("a.xdb", r#"<A><Next href="b.xdb"/></A>"#),
+            ("b.xdb", r#"<B><Back href="a.xdb"/></B>"#)
+        ]);
+        let value = file_to_value("a.xdb", &files, 10).unwrap();
+        let back = &value["A"]["Next"]["@resolved"]["B"]["Back"];
+        assert_eq!(back["@cycle"], json!(true));
+        assert!(back.get("@resolved").is_none());
+    }
+
+    #[test]
+    fn file_referenced_twice_is_not_cycle() {
+        let files = files(&[
+            ("a.xdb", r#"<A><First href="b.xdb"/><Second href="b.xdb"/></A>"#),
+            ("b.xdb", "<B>text</B>")
+        ]);
+        let value = file_to_value("a.xdb", &files, 10).unwrap();
+        assert_eq!(value["A"]["First"]["@resolved"], json!({"B": "text"}));
+        assert_eq!(value["A"]["Second"]["@resolved"], json!({"B": "text"}));
+        assert!(value["A"]["Second"].get("@cycle").is_none());
+    }
+}